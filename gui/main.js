@@ -2,32 +2,141 @@ class Status {
   #state = null;
   #tasksContainer = document.getElementById('tasks');
   #queueContainer = document.getElementById('queue');
+  #failedContainer = document.getElementById('failed');
   #libraryContainer = document.getElementById('library');
+  #pauseInput = document.getElementById('pause');
+  #notice = document.getElementById('notice');
+  #reconcileButton = document.getElementById('reconcile');
+  #importButton = document.getElementById('import');
+  #logContainer = document.getElementById('download-log');
+
+  constructor() {
+    this.#pauseInput.addEventListener('change', () => this.setPaused(this.#pauseInput.value));
+    this.#reconcileButton.addEventListener('click', () => this.reconcile());
+    this.#importButton.addEventListener('click', () => this.import());
+  }
 
   display() {
+    this.#pauseInput.value = this.#state.paused;
+    if (this.#state.disk_space_error != null) {
+      this.#notice.textContent = `${this.#state.disk_space_error}, downloads are stopped`;
+    } else {
+      this.#notice.innerHTML = this.#state.low_disk_space ? 'Low disk space, downloads are stopped' : '';
+    }
     this.#tasksContainer.innerHTML = '';
     this.#queueContainer.innerHTML = '';
+    this.#failedContainer.innerHTML = '';
     this.#libraryContainer.innerHTML = '';
     this.#state.tasks.forEach((e) => {
-      const item = document.createElement('div');
-      item.className = 'media-item';
-      item.innerHTML = `<div class="media-title">${e.title}</div><div>${e.link}</div>`;
+      const item = this.queueItem(e);
+      const progress = document.createElement('div');
+      progress.className = 'progress';
+      progress.innerHTML = formatProgress(e.progress);
+      item.insertBefore(progress, item.querySelector('button'));
       this.#tasksContainer.appendChild(item);
     });
     this.#state.queue.forEach((e) => {
+      this.#queueContainer.appendChild(this.queueItem(e));
+    });
+    this.#state.failed.forEach((e) => {
       const item = document.createElement('div');
       item.className = 'media-item';
-      item.innerHTML = `<div class="media-title">${e.title}</div><div>${e.link}</div>`;
-      this.#queueContainer.appendChild(item);
+      // The error contains the output of yt-dlp, which must not be parsed as HTML
+      item.append(
+        textElement('div', e.title, 'media-title'),
+        textElement('div', e.link),
+        textElement('div', e.failure.error),
+        this.logButton(e.link),
+      );
+      this.#failedContainer.appendChild(item);
     });
     this.#state.library.forEach((e) => {
       const item = document.createElement('div');
       item.className = 'media-item';
       item.innerHTML = `<div class="media-title">${e.title}</div>`;
+      if (e.missing) {
+        item.innerHTML += '<div>File is missing</div>';
+      }
+      const protectButton = document.createElement('button');
+      protectButton.innerHTML = e.protected ? 'Unprotect' : 'Protect';
+      protectButton.title = 'Protected media are never deleted by retention rules';
+      protectButton.addEventListener('click', () => this.setProtected(e, !e.protected));
+      item.append(protectButton, this.logButton(e.source));
       this.#libraryContainer.appendChild(item);
     });
   }
 
+  queueItem(e) {
+    const item = document.createElement('div');
+    item.className = 'media-item';
+    item.innerHTML = `<div class="media-title">${e.title}</div><div>${e.link}</div>`;
+    const cancelButton = document.createElement('button');
+    cancelButton.innerHTML = 'Cancel';
+    cancelButton.addEventListener('click', () => this.cancel(e, false));
+    const blockButton = document.createElement('button');
+    blockButton.innerHTML = 'Block';
+    blockButton.addEventListener('click', () => this.cancel(e, true));
+    item.append(cancelButton, blockButton);
+    if (e.failure != null) {
+      item.appendChild(this.logButton(e.link));
+    }
+    return item;
+  }
+
+  logButton(link) {
+    const logButton = document.createElement('button');
+    logButton.innerHTML = 'Log';
+    logButton.title = 'Output of yt-dlp for each attempt';
+    logButton.addEventListener('click', () => this.showLog(link));
+    return logButton;
+  }
+
+  showLog(link) {
+    fetch(`/downloads/log?link=${encodeURIComponent(link)}`)
+      .then((response) => response.ok ? response.json() : [])
+      .then((logs) => {
+        this.#logContainer.textContent = logs
+          .map((l) => `${new Date(l.time).toLocaleString()} ${l.success ? 'succeeded' : 'failed'}\n${l.stderr}${l.stdout}`)
+          .join('\n') || 'No log stored';
+      });
+  }
+
+  setPaused(paused) {
+    fetch('/pause', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(paused),
+    }).then(() => this.load());
+  }
+
+  cancel(e, block) {
+    fetch('/cancel', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ id: e.id, block: block }),
+    }).then(() => this.load());
+  }
+
+  reconcile() {
+    fetch('/library/reconcile', { method: 'POST' }).then(() => this.load());
+  }
+
+  import() {
+    fetch('/library/import', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({}),
+    }).then(() => this.load());
+  }
+
+  setProtected(e, isProtected) {
+    fetch('/library/protect', {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({ link: e.source, protected: isProtected }),
+    }).then(() => this.load());
+  }
+
   load() {
     fetch('/state')
       .then((response) => response.json())
@@ -36,6 +145,42 @@ class Status {
         this.display();
       });
   }
+
+  setProgress(link, progress) {
+    const task = this.#state?.tasks.find((t) => t.link == link);
+    if (task != null) {
+      task.progress = progress;
+      this.display();
+    }
+  }
+}
+
+function textElement(tag, text, className) {
+  const element = document.createElement(tag);
+  element.textContent = text ?? '';
+  if (className != null) {
+    element.className = className;
+  }
+  return element;
+}
+
+function formatProgress(progress) {
+  if (progress == null) {
+    return 'Starting..';
+  }
+  const mb = (bytes) => (bytes / 1e6).toFixed(1);
+  let text = progress.percent == null
+    ? `${mb(progress.downloaded_bytes)} MB`
+    : `${progress.percent.toFixed(0)}% of ${mb(progress.total_bytes)} MB`;
+  if (progress.speed != null) {
+    text += `, ${mb(progress.speed)} MB/s`;
+  }
+  if (progress.eta != null) {
+    const minutes = Math.floor(progress.eta / 60);
+    const seconds = String(progress.eta % 60).padStart(2, '0');
+    text += `, ${minutes}:${seconds} left`;
+  }
+  return text;
 }
 
 async function getFeed(url) {
@@ -57,17 +202,39 @@ class Sources {
   #addButton = document.getElementById('source-add');
   #urlInput = document.getElementById('source-url');
   #typeInput = document.getElementById('source-type');
+  #intervalInput = document.getElementById('source-interval');
+  #scheduleInput = document.getElementById('source-schedule');
+  #rateLimitInput = document.getElementById('source-rate-limit');
+  #historyContainer = document.getElementById('source-history');
+  #previewButton = document.getElementById('sync-preview');
+  #previewContainer = document.getElementById('sync-preview-entries');
 
   constructor() {
     this.#addButton.addEventListener('click', () => this.addSource());
+    this.#previewButton.addEventListener('click', () => this.preview());
   }
 
   display() {
-    this.#container.innerHTML = `<tr><th>URL</th><th>Type</th><th></th></tr>`;
+    this.#container.innerHTML = `<tr><th>URL</th><th>Type</th><th>Sync interval</th><th>Sync schedule</th><th>Rate limit</th><th>Health</th><th></th><th></th><th></th></tr>`;
     let i = 0;
     this.#sources.forEach((e) => {
       const row = document.createElement('tr');
-      row.innerHTML = `<td>${e.url}</td><td>${e.type}</td>`;
+      row.innerHTML = `<td>${e.url}</td><td>${e.type}</td><td>${e.sync_interval ?? ''}</td><td>${e.sync_schedule ?? ''}</td><td>${e.rate_limit ?? ''}</td><td>${this.health(e)}</td>`;
+      if (e.unhealthy) {
+        row.className = 'unhealthy';
+      }
+      const pauseButton = document.createElement('td');
+      pauseButton.className = 'pause-btn';
+      pauseButton.innerHTML = e.paused ? '▶' : '⏸';
+      pauseButton.title = e.paused ? 'Resume' : 'Pause';
+      pauseButton.addEventListener('click', () => this.togglePaused(e));
+      row.appendChild(pauseButton);
+      const historyButton = document.createElement('td');
+      historyButton.className = 'history-btn';
+      historyButton.innerHTML = '🕘';
+      historyButton.title = 'Sync history';
+      historyButton.addEventListener('click', () => this.showHistory(e));
+      row.appendChild(historyButton);
       const removeButton = document.createElement('td');
       removeButton.className = 'remove-btn';
       removeButton.innerHTML = '✖';
@@ -100,13 +267,84 @@ class Sources {
     const url = this.#urlInput.value;
     const feedUrl = await getFeed(url);
     const type = this.#typeInput.value;
+    const interval = this.#intervalInput.valueAsNumber;
+    const schedule = this.#scheduleInput.value;
+    const rateLimit = this.#rateLimitInput.value;
     this.#urlInput.value = '';
+    this.#intervalInput.value = '';
+    this.#scheduleInput.value = '';
+    this.#rateLimitInput.value = '';
     const source = { url: feedUrl, type: type };
+    if (!isNaN(interval)) {
+      source.sync_interval = interval;
+    }
+    if (schedule != '') {
+      source.sync_schedule = schedule;
+    }
+    if (rateLimit != '') {
+      source.rate_limit = rateLimit;
+    }
     this.#sources.push(source)
     this.display();
     this.save();
   }
 
+  health(source) {
+    const lastSuccess = source.last_success == null ? 'never' : new Date(source.last_success).toLocaleString();
+    if (source.failures == 0) {
+      return source.last_success == null ? '' : 'OK';
+    }
+    return `${source.failures} failed syncs, last success: ${lastSuccess}`;
+  }
+
+  showHistory(source) {
+    this.#historyContainer.innerHTML = '';
+    if (source.id == null) {
+      return;
+    }
+    fetch(`/sources/${source.id}/history`)
+      .then((response) => response.json())
+      .then((runs) => {
+        this.#historyContainer.innerHTML = `<tr><th>Time</th><th>Status</th><th>Entries</th><th>Queued</th><th>Duration</th><th>Error</th></tr>`;
+        runs.forEach((r) => {
+          const row = document.createElement('tr');
+          row.innerHTML = `<td>${new Date(r.time).toLocaleString()}</td><td>${r.status ?? ''}</td><td>${r.entries}</td><td>${r.queued}</td><td>${r.duration} ms</td>`;
+          // Errors can contain text from the feed host
+          row.appendChild(textElement('td', r.error));
+          this.#historyContainer.appendChild(row);
+        });
+      });
+  }
+
+  preview() {
+    this.#previewContainer.innerHTML = '';
+    fetch('/sync/preview', { method: 'POST' })
+      .then((response) => response.json())
+      .then((entries) => {
+        this.#previewContainer.innerHTML = `<tr><th>Title</th><th>Link</th><th>Verdict</th><th>Reason</th></tr>`;
+        entries.forEach((e) => {
+          // Titles and links come straight from the feeds
+          const row = document.createElement('tr');
+          const link = document.createElement('td');
+          if (/^https?:\/\//i.test(e.link)) {
+            const anchor = textElement('a', e.link);
+            anchor.href = e.link;
+            link.appendChild(anchor);
+          } else {
+            link.textContent = e.link;
+          }
+          row.append(textElement('td', e.title), link, textElement('td', e.verdict), textElement('td', e.reason));
+          this.#previewContainer.appendChild(row);
+        });
+      });
+  }
+
+  togglePaused(source) {
+    source.paused = !source.paused;
+    this.display();
+    this.save();
+  }
+
   removeSource(index) {
     this.#sources.splice(index, 1);
     this.display();
@@ -117,18 +355,48 @@ class Sources {
 const DataType = Object.freeze({
   STRING: Symbol('string'),
   NUMBER: Symbol('number'),
+  JSON: Symbol('json'),
 });
 
 const configMap = {
   sync_interval: DataType.NUMBER,
+  sync_schedule: DataType.STRING,
+  sync_jitter: DataType.NUMBER,
   parallel_downloads: DataType.NUMBER,
+  download_windows: DataType.JSON,
   media_dir: DataType.STRING,
+  rate_limit: DataType.STRING,
+  min_free_space: DataType.STRING,
   address: DataType.STRING,
   port: DataType.NUMBER,
+  download_log_retention: DataType.STRING,
+  shutdown_timeout: DataType.STRING,
+  reconcile_interval: DataType.STRING,
+  missing_files: DataType.STRING,
   download_filter: {
     max_age: DataType.STRING,
     before: DataType.STRING,
     after: DataType.STRING,
+  },
+  retry: {
+    max_attempts: DataType.NUMBER,
+    initial_delay: DataType.STRING,
+    factor: DataType.NUMBER,
+    max_delay: DataType.STRING,
+  },
+  unhealthy_after: DataType.NUMBER,
+  disable_after: DataType.NUMBER,
+  crawl: {
+    concurrency: DataType.NUMBER,
+    host_concurrency: DataType.NUMBER,
+    host_interval: DataType.STRING,
+    hosts: DataType.JSON,
+    max_backoff: DataType.STRING,
+  },
+  retention: {
+    keep_last: DataType.NUMBER,
+    max_age: DataType.STRING,
+    max_size: DataType.STRING,
   }
 }
 
@@ -169,7 +437,10 @@ class Config {
 
   displayConfig(inputs, config, prefix) {
     for (const [key, value] of Object.entries(config)) {
-      if (value === Object(value)) {
+      const input = prefix == null ? inputs[key] : inputs[prefix][key];
+      if (input.type == DataType.JSON) {
+        input.element.value = JSON.stringify(value, null, 2);
+      } else if (value === Object(value)) {
         this.displayConfig(inputs, value, key);
       } else {
         if (prefix == null) {
@@ -209,10 +480,12 @@ class Config {
         value = input.element.valueAsNumber;
       } else if (input.type == DataType.STRING) {
         value = input.element.value;
+      } else if (input.type == DataType.JSON) {
+        value = input.element.value == '' ? '' : JSON.parse(input.element.value);
       } else {
         console.error('Unkown data type!', input);
       }
-      if (value != "") {
+      if (value !== "" && !Number.isNaN(value)) {
         config[key] = value;
       }
     }
@@ -228,3 +501,21 @@ status2.load();
 sources.load();
 config.load();
 
+const events = new EventSource('/events');
+events.addEventListener('message', (message) => {
+  const event = JSON.parse(message.data);
+  switch (event.type) {
+    case 'download_progress':
+      status2.setProgress(event.link, event.progress);
+      break;
+    case 'config_reloaded':
+      config.load();
+      break;
+    case 'sources_reloaded':
+      sources.load();
+      break;
+    default:
+      status2.load();
+  }
+});
+
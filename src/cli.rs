@@ -0,0 +1,267 @@
+use crate::{
+    config::{Config, Dirs},
+    daemon::{Daemon, Options, Verdict},
+    db::Database,
+    dl,
+    error::Error,
+    events::Events,
+    media::MediaEntry,
+    source::{Source, SourceType, Sources},
+};
+use chrono::prelude::*;
+use clap::{Parser, Subcommand};
+use std::{fs, path::PathBuf, process::ExitCode};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::unbounded_channel,
+};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Downloads new videos and audio from feeds with yt-dlp"
+)]
+pub struct Cli {
+    /// Directory with the config and sources [default: ~/.config/drainpipe]
+    #[arg(long, global = true)]
+    pub config_dir: Option<PathBuf>,
+    /// Directory with the database [default: ~/.local/share/drainpipe]
+    #[arg(long, global = true)]
+    pub data_dir: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the daemon with the web interface (default)
+    Daemon,
+    /// Sync the sources and add new entries to the download queue, without downloading them
+    Sync {
+        /// Sync every source once and exit
+        #[arg(long)]
+        once: bool,
+        /// Only show what would be queued, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Sync all sources, download everything new and exit, without the web interface. Exits with
+    /// an error if any sync or download failed, or downloads could not start.
+    RunOnce {
+        /// Only show what would be downloaded, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Add a source
+    AddSource {
+        /// URL of the feed
+        url: String,
+        #[arg(long = "type", value_enum, default_value_t = SourceType::Video)]
+        r#type: SourceType,
+    },
+    /// List the sources and their health
+    ListSources,
+    /// Download a single video or audio to the library
+    Download {
+        url: String,
+        #[arg(long = "type", value_enum, default_value_t = SourceType::Video)]
+        r#type: SourceType,
+    },
+    /// Show the library
+    Library {
+        #[command(subcommand)]
+        command: LibraryCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum LibraryCommand {
+    /// List the downloaded media
+    List,
+}
+
+pub async fn run(command: Command, dirs: &Dirs) -> Result<ExitCode, Error> {
+    match command {
+        Command::Daemon => daemon(dirs, Options::default()).await,
+        Command::Sync { dry_run: true, .. } | Command::RunOnce { dry_run: true } => {
+            preview_sync(dirs).await
+        }
+        Command::Sync { once: false, .. } => {
+            let options = Options {
+                web: false,
+                downloads: false,
+            };
+            daemon(dirs, options).await
+        }
+        Command::Sync { once: true, .. } => sync_once(dirs).await,
+        Command::RunOnce { .. } => run_once(dirs).await,
+        Command::AddSource { url, r#type } => add_source(dirs, url, r#type),
+        Command::ListSources => list_sources(dirs).await,
+        Command::Download { url, r#type } => download(dirs, url, r#type).await,
+        Command::Library {
+            command: LibraryCommand::List,
+        } => list_library(dirs).await,
+    }
+}
+
+async fn daemon(dirs: &Dirs, options: Options) -> Result<ExitCode, Error> {
+    let d = Daemon::start(dirs, options).await?;
+    d.run(shutdown_signal()).await?;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn sync_once(dirs: &Dirs) -> Result<ExitCode, Error> {
+    let options = Options {
+        web: false,
+        downloads: false,
+    };
+    let failed = Daemon::start(dirs, options).await?.sync_once().await?;
+    if failed > 0 {
+        eprintln!("Failed to sync {failed} sources");
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn run_once(dirs: &Dirs) -> Result<ExitCode, Error> {
+    let options = Options {
+        web: false,
+        downloads: true,
+    };
+    let d = Daemon::start(dirs, options).await?;
+    let failed = d.run_once(shutdown_signal()).await?;
+    if failed > 0 {
+        eprintln!("{failed} syncs or downloads failed or could not start");
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn preview_sync(dirs: &Dirs) -> Result<ExitCode, Error> {
+    let options = Options {
+        web: false,
+        downloads: false,
+    };
+    let previews = Daemon::start(dirs, options).await?.preview_sync().await?;
+    for p in previews {
+        let verdict = match (p.verdict, p.reason) {
+            (Verdict::Download, _) => "download".to_string(),
+            (Verdict::Skip, reason) => format!("skip ({})", reason.unwrap_or_default()),
+        };
+        let title = p.entry.title.unwrap_or_default();
+        println!("{}\t{title}\t{verdict}", p.entry.link);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn add_source(dirs: &Dirs, url: String, r#type: SourceType) -> Result<ExitCode, Error> {
+    dirs.create()?;
+    let mut sources = Sources::load(&dirs.sources_path())?;
+    let mut list = sources.get();
+    if list.iter().any(|s| s.url == url) {
+        return Err(Error::Custom(format!("Source '{url}' already exists")));
+    }
+    println!("Added {type} source '{url}'");
+    list.push(Source::new(url, r#type));
+    sources.set(list)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn list_sources(dirs: &Dirs) -> Result<ExitCode, Error> {
+    dirs.create()?;
+    let config = Config::load(&dirs.config_path())?;
+    let sources = Sources::load(&dirs.sources_path())?;
+    let db = Database::load(&dirs.database_path()).await?;
+    let health = db.get_source_health().await?;
+    for source in sources.get() {
+        let status = match health.get(&source.url) {
+            _ if source.paused => "paused".to_string(),
+            None => "never synced".to_string(),
+            Some(h) if h.failures >= config.data.unhealthy_after.max(1) => {
+                format!("unhealthy, {} failed syncs", h.failures)
+            }
+            Some(_) => "ok".to_string(),
+        };
+        println!("{}\t{}\t{status}", source.url, source.r#type);
+    }
+    db.close().await;
+    Ok(ExitCode::SUCCESS)
+}
+
+async fn download(dirs: &Dirs, url: String, r#type: SourceType) -> Result<ExitCode, Error> {
+    dirs.create()?;
+    let config = Config::load(&dirs.config_path())?.data;
+    let db = Database::load(&dirs.database_path()).await?;
+    if let Some(media) = db.get(&url).await? {
+        println!("Already downloaded to '{}'", media.path);
+        db.close().await;
+        return Ok(ExitCode::SUCCESS);
+    }
+    fs::create_dir_all(&config.media_dir)?;
+    let entry = MediaEntry {
+        id: None,
+        title: None,
+        link: url,
+        published: None,
+        r#type,
+        source: None,
+        failure: None,
+    };
+    let (dl_tx, mut dl_rx) = unbounded_channel();
+    let task = dl::download_video(
+        config.media_dir,
+        entry,
+        config.rate_limit,
+        dl_tx,
+        Events::default(),
+    );
+    let code = tokio::select! {
+        Some((entry, result, log)) = dl_rx.recv() => {
+            if let Some(log) = &log {
+                db.insert_download_log(&entry.link, log).await?;
+            }
+            match result {
+                Ok(media) => {
+                    db.insert(&media).await?;
+                    println!("Downloaded '{}' to '{}'", media.title, media.path);
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("Download failed: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        _ = shutdown_signal() => {
+            task.kill().await;
+            ExitCode::FAILURE
+        }
+    };
+    db.close().await;
+    Ok(code)
+}
+
+async fn list_library(dirs: &Dirs) -> Result<ExitCode, Error> {
+    dirs.create()?;
+    let db = Database::load(&dirs.database_path()).await?;
+    for media in db.get_all().await? {
+        let date = Utc
+            .timestamp_opt(media.date, 0)
+            .single()
+            .map(|d| d.with_timezone(&Local).format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let missing = if media.missing { "\t(missing)" } else { "" };
+        println!("{date}\t{}\t{}{missing}", media.title, media.path);
+    }
+    db.close().await;
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Completes on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
+    }
+}
@@ -1,22 +1,107 @@
 use crate::{error::Error, media::MediaEntry};
 use chrono::{prelude::*, Duration};
+use cron::Schedule;
 use serde::{Deserialize, Serialize};
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::{Path, PathBuf},
 };
 use tracing::info;
 
+const DIR_NAME: &str = "drainpipe";
+
+/// Directories of a drainpipe instance, instances with their own directories can run side by side
+#[derive(Debug, Clone)]
+pub struct Dirs {
+    /// Contains the config and the sources
+    pub config: PathBuf,
+    /// Contains the database
+    pub data: PathBuf,
+}
+
+impl Dirs {
+    /// Uses the default directories for the ones that are not given
+    pub fn new(config: Option<PathBuf>, data: Option<PathBuf>) -> Self {
+        Self {
+            config: config.unwrap_or_else(|| dirs::config_dir().unwrap().join(DIR_NAME)),
+            data: data.unwrap_or_else(|| dirs::data_dir().unwrap().join(DIR_NAME)),
+        }
+    }
+
+    pub fn create(&self) -> Result<(), Error> {
+        std::fs::create_dir_all(&self.config)?;
+        std::fs::create_dir_all(&self.data)?;
+        Ok(())
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.config.join("config.yaml")
+    }
+
+    pub fn sources_path(&self) -> PathBuf {
+        self.config.join("sources.yaml")
+    }
+
+    pub fn database_path(&self) -> PathBuf {
+        self.data.join("library.db")
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default)]
 pub struct ConfigData {
     pub address: IpAddr,
     pub port: u16,
     pub sync_interval: u64,
+    /// Cron expression, overrides the sync interval
+    #[serde(
+        with = "crate::file::cron_schedule_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sync_schedule: Option<Schedule>,
+    /// Maximum random delay in seconds added to the sync interval of each source
+    pub sync_jitter: u64,
     pub parallel_downloads: u64,
+    /// Times at which downloads may be started, downloads are always allowed if empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub download_windows: Vec<DownloadWindow>,
     pub media_dir: PathBuf,
+    /// Maximum download rate in bytes per second, shared by all downloads
+    #[serde(
+        with = "crate::file::byte_size_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rate_limit: Option<u64>,
+    /// Don't start downloads when there is less free space in the media directory
+    #[serde(
+        with = "crate::file::byte_size_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_free_space: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub download_filter: Option<DownloadFilter>,
+    pub retry: RetryConfig,
+    pub crawl: CrawlConfig,
+    /// Number of consecutive failed syncs after which a source is reported as unhealthy
+    pub unhealthy_after: u32,
+    /// Number of consecutive failed syncs after which a source is paused, 0 to never pause
+    pub disable_after: u32,
+    /// Interval at which the library is checked for downloads whose files were removed, 0 to only
+    /// check when requested
+    #[serde(with = "crate::file::dhms_duration")]
+    pub reconcile_interval: Duration,
+    /// What to do with downloads whose files were removed
+    pub missing_files: MissingFiles,
+    /// Rules for deleting old downloads, applied to every source without its own rules
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    /// Time the output of yt-dlp is kept for each download
+    #[serde(with = "crate::file::dhms_duration")]
+    pub download_log_retention: Duration,
+    /// Time to wait for running downloads to finish when shutting down
+    #[serde(with = "crate::file::dhms_duration")]
+    pub shutdown_timeout: Duration,
 }
 
 impl Default for ConfigData {
@@ -25,13 +110,52 @@ impl Default for ConfigData {
             address: Ipv4Addr::UNSPECIFIED.into(),
             port: 9193,
             sync_interval: 900,
+            sync_schedule: None,
+            sync_jitter: 60,
             parallel_downloads: 1,
+            download_windows: Vec::new(),
             media_dir: dirs::home_dir().unwrap().join("media"),
+            rate_limit: None,
+            min_free_space: None,
             download_filter: Some(DownloadFilter::default()),
+            retry: RetryConfig::default(),
+            crawl: CrawlConfig::default(),
+            unhealthy_after: 3,
+            disable_after: 100,
+            reconcile_interval: Duration::days(1),
+            missing_files: MissingFiles::default(),
+            retention: None,
+            download_log_retention: Duration::days(7),
+            shutdown_timeout: Duration::seconds(8),
         }
     }
 }
 
+impl ConfigData {
+    pub fn web_address(&self) -> SocketAddr {
+        SocketAddr::new(self.address, self.port)
+    }
+
+    /// Returns the number of downloads that may run at the given time
+    pub fn download_limit(&self, now: DateTime<Local>) -> u64 {
+        if self.download_windows.is_empty() {
+            return self.parallel_downloads;
+        }
+        match self.download_windows.iter().find(|w| w.contains(now)) {
+            Some(window) => window.parallel_downloads.unwrap_or(self.parallel_downloads),
+            None => 0,
+        }
+    }
+
+    /// Returns the next time a download window opens
+    pub fn next_download_window(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        self.download_windows
+            .iter()
+            .filter_map(|w| w.next_start(now))
+            .min()
+    }
+}
+
 pub struct Config {
     path: PathBuf,
     pub from_env: bool,
@@ -69,6 +193,91 @@ impl Config {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DownloadWindow {
+    /// Days on which the window applies, every day if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+    /// Time range of the window, the whole day if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hours: Option<TimeRange>,
+    /// Overrides the global number of parallel downloads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_downloads: Option<u64>,
+}
+
+impl DownloadWindow {
+    fn applies_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains(&self, now: DateTime<Local>) -> bool {
+        let day = now.weekday();
+        let time = now.time();
+        match &self.hours {
+            None => self.applies_on(day),
+            Some(TimeRange { start, end }) if start <= end => {
+                self.applies_on(day) && *start <= time && time < *end
+            }
+            // The window continues past midnight, so the part after midnight belongs to the
+            // window that started on the previous day
+            Some(TimeRange { start, end }) => {
+                (self.applies_on(day) && *start <= time)
+                    || (self.applies_on(day.pred()) && time < *end)
+            }
+        }
+    }
+
+    fn next_start(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = self
+            .hours
+            .as_ref()
+            .map(|h| h.start)
+            .unwrap_or_else(|| NaiveTime::from_hms_opt(0, 0, 0).unwrap());
+        (0..=7)
+            .map(|n| now.date_naive() + Duration::days(n))
+            .filter(|date| self.applies_on(date.weekday()))
+            .filter_map(|date| date.and_time(start).and_local_timezone(Local).earliest())
+            .find(|t| *t > now)
+    }
+}
+
+/// Range of the day in the format `HH:MM-HH:MM`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = String;
+
+    fn try_from(str: String) -> Result<Self, Self::Error> {
+        let (start, end) = str
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid time range '{str}', expected HH:MM-HH:MM"))?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .map_err(|e| format!("Invalid time '{t}': {e}"))
+        };
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+impl From<TimeRange> for String {
+    fn from(range: TimeRange) -> Self {
+        format!(
+            "{}-{}",
+            range.start.format("%H:%M"),
+            range.end.format("%H:%M")
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DownloadFilter {
     #[serde(with = "crate::file::dhms_duration_option")]
@@ -80,28 +289,29 @@ pub struct DownloadFilter {
 }
 
 impl DownloadFilter {
-    pub fn filter(&self, entry: &MediaEntry) -> bool {
+    /// Returns why the entry is filtered out, or `None` if it passes the filter
+    pub fn filter(&self, entry: &MediaEntry) -> Option<String> {
         if let Some(published) = entry.published {
             if let Some(before) = self.before {
                 if published
                     > DateTime::<Utc>::from_local(before.and_hms_opt(0, 0, 0).unwrap(), Utc)
                 {
-                    return true;
+                    return Some(format!("published after {before}"));
                 }
             }
             if let Some(after) = self.after {
                 if published < DateTime::<Utc>::from_local(after.and_hms_opt(0, 0, 0).unwrap(), Utc)
                 {
-                    return true;
+                    return Some(format!("published before {after}"));
                 }
             }
             if let Some(max_age) = self.max_age {
                 if Utc::now() - published > max_age {
-                    return true;
+                    return Some("older than max_age".to_string());
                 }
             }
         }
-        false
+        None
     }
 }
 
@@ -114,3 +324,340 @@ impl Default for DownloadFilter {
         }
     }
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Number of attempts after which a download is marked as permanently failed
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    #[serde(with = "crate::file::dhms_duration")]
+    pub initial_delay: Duration,
+    /// Multiplier applied to the delay after every failed attempt
+    pub factor: u32,
+    #[serde(with = "crate::file::dhms_duration")]
+    pub max_delay: Duration,
+}
+
+impl RetryConfig {
+    /// Returns the delay before the next attempt after the given number of failed attempts
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = i64::from(self.factor).saturating_pow(attempts.saturating_sub(1));
+        self.initial_delay
+            .num_milliseconds()
+            .checked_mul(factor)
+            .map_or(self.max_delay, |ms| {
+                Duration::milliseconds(ms).min(self.max_delay)
+            })
+    }
+
+    /// Returns the time of the next attempt after the given number of failed attempts, `None` if
+    /// the download failed permanently
+    pub fn retry_at(&self, attempts: u32, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        now.checked_add_signed(self.delay(attempts))
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::minutes(10),
+            factor: 2,
+            max_delay: Duration::days(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MissingFiles {
+    /// Keep the download in the library, marked as missing
+    #[default]
+    Mark,
+    /// Remove the download from the library and download it again
+    Requeue,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CrawlConfig {
+    /// Maximum number of feeds fetched at once
+    pub concurrency: usize,
+    /// Maximum number of feeds fetched at once from the same host
+    pub host_concurrency: usize,
+    /// Minimum time between two requests to the same host
+    #[serde(with = "crate::file::dhms_duration")]
+    pub host_interval: Duration,
+    /// Overrides the host limits for specific hosts, e.g. `www.youtube.com`
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub hosts: HashMap<String, HostLimits>,
+    /// Longest time to wait for a host that responded with `429 Too Many Requests`, if it asks to
+    /// wait longer its feeds are skipped until the backoff is over
+    #[serde(with = "crate::file::dhms_duration")]
+    pub max_backoff: Duration,
+}
+
+impl CrawlConfig {
+    /// Returns the maximum number of concurrent requests and the minimum time between requests
+    /// for a host
+    pub fn host_limits(&self, host: &str) -> (usize, Duration) {
+        let limits = self.hosts.get(host);
+        (
+            limits
+                .and_then(|l| l.concurrency)
+                .unwrap_or(self.host_concurrency),
+            limits
+                .and_then(|l| l.interval)
+                .unwrap_or(self.host_interval),
+        )
+    }
+}
+
+impl Default for CrawlConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 128,
+            host_concurrency: 4,
+            host_interval: Duration::zero(),
+            hosts: HashMap::new(),
+            max_backoff: Duration::minutes(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    #[serde(
+        default,
+        with = "crate::file::dhms_duration_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub interval: Option<Duration>,
+}
+
+/// Rules for deleting old downloads of a source, protected media are never deleted and don't
+/// count towards the limits
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    /// Number of most recent downloads to keep
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<usize>,
+    /// Delete downloads older than this
+    #[serde(
+        with = "crate::file::dhms_duration_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_age: Option<Duration>,
+    /// Maximum total size of the downloads, the oldest ones are deleted first
+    #[serde(
+        with = "crate::file::byte_size_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_size: Option<u64>,
+}
+
+impl Retention {
+    /// Returns why a download should be deleted, if at all. Downloads are checked from newest to
+    /// oldest, `index` is the position of the download and `total_size` the size of it and all
+    /// newer downloads.
+    pub fn check(
+        &self,
+        index: usize,
+        date: DateTime<Utc>,
+        total_size: u64,
+        now: DateTime<Utc>,
+    ) -> Option<String> {
+        if let Some(keep_last) = self.keep_last {
+            if index >= keep_last {
+                return Some(format!("only the last {keep_last} downloads are kept"));
+            }
+        }
+        if let Some(max_age) = self.max_age {
+            if now - date > max_age {
+                return Some("older than the maximum age".to_string());
+            }
+        }
+        if let Some(max_size) = self.max_size {
+            if total_size > max_size {
+                return Some("the maximum total size is exceeded".to_string());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Local time on Wednesday, 3 January 2024
+    fn wed(hour: u32, min: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 3, hour, min, 0).unwrap()
+    }
+
+    fn window(yaml: &str) -> DownloadWindow {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn parse_time_range() {
+        let range = TimeRange::try_from(" 22:30 - 06:00".to_string()).unwrap();
+        assert_eq!(range.start, NaiveTime::from_hms_opt(22, 30, 0).unwrap());
+        assert_eq!(range.end, NaiveTime::from_hms_opt(6, 0, 0).unwrap());
+        assert_eq!(String::from(range), "22:30-06:00");
+        assert!(TimeRange::try_from("22:30".to_string()).is_err());
+        assert!(TimeRange::try_from("25:00-06:00".to_string()).is_err());
+    }
+
+    #[test]
+    fn window_within_day() {
+        let window = window("hours: 01:00-07:00");
+        assert!(!window.contains(wed(0, 59)));
+        assert!(window.contains(wed(1, 0)));
+        assert!(window.contains(wed(6, 59)));
+        assert!(!window.contains(wed(7, 0)));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let window = window("{ days: [wed], hours: 22:00-06:00 }");
+        assert!(window.contains(wed(23, 0)));
+        // The early hours of Thursday belong to the window that started on Wednesday
+        assert!(window.contains(wed(3, 0) + Duration::days(1)));
+        assert!(!window.contains(wed(3, 0)));
+        assert!(!window.contains(wed(12, 0)));
+        assert!(!window.contains(wed(23, 0) + Duration::days(1)));
+    }
+
+    #[test]
+    fn window_weekdays() {
+        let window = window("days: [sat, sun]");
+        assert!(!window.contains(wed(12, 0)));
+        assert!(window.contains(wed(0, 0) + Duration::days(3)));
+        assert!(window.contains(wed(23, 59) + Duration::days(4)));
+        assert!(!window.contains(wed(0, 0) + Duration::days(5)));
+    }
+
+    #[test]
+    fn next_window_start() {
+        let window = window("{ days: [fri], hours: 01:00-07:00 }");
+        assert_eq!(
+            window.next_start(wed(12, 0)),
+            Some(wed(1, 0) + Duration::days(2))
+        );
+        // Inside the window the next start is a week later
+        let friday = wed(2, 0) + Duration::days(2);
+        assert_eq!(
+            window.next_start(friday),
+            Some(wed(1, 0) + Duration::days(9))
+        );
+        let every_day = DownloadWindow {
+            days: Vec::new(),
+            hours: None,
+            parallel_downloads: None,
+        };
+        assert_eq!(
+            every_day.next_start(wed(12, 0)),
+            Some(wed(0, 0) + Duration::days(1))
+        );
+    }
+
+    #[test]
+    fn download_limit() {
+        let mut config = ConfigData {
+            parallel_downloads: 3,
+            ..Default::default()
+        };
+        assert_eq!(config.download_limit(wed(12, 0)), 3);
+        config.download_windows = vec![
+            window("hours: 01:00-07:00"),
+            window("{ days: [wed], hours: 20:00-23:00, parallel_downloads: 1 }"),
+        ];
+        assert_eq!(config.download_limit(wed(2, 0)), 3);
+        assert_eq!(config.download_limit(wed(12, 0)), 0);
+        assert_eq!(config.download_limit(wed(21, 0)), 1);
+        assert_eq!(config.next_download_window(wed(12, 0)), Some(wed(20, 0)));
+    }
+
+    #[test]
+    fn retry_delay() {
+        let retry = RetryConfig {
+            max_attempts: 10,
+            initial_delay: Duration::minutes(10),
+            factor: 2,
+            max_delay: Duration::hours(1),
+        };
+        assert_eq!(retry.delay(1), Duration::minutes(10));
+        assert_eq!(retry.delay(2), Duration::minutes(20));
+        assert_eq!(retry.delay(3), Duration::minutes(40));
+        assert_eq!(retry.delay(4), Duration::hours(1));
+        // factor^attempts overflows long before this
+        assert_eq!(retry.delay(100), Duration::hours(1));
+        assert_eq!(retry.delay(u32::MAX), Duration::hours(1));
+    }
+
+    #[test]
+    fn retry_delay_without_factor() {
+        let retry = RetryConfig {
+            factor: 0,
+            ..Default::default()
+        };
+        assert_eq!(retry.delay(1), retry.initial_delay);
+        assert_eq!(retry.delay(2), Duration::zero());
+        assert_eq!(retry.delay(u32::MAX), Duration::zero());
+    }
+
+    #[test]
+    fn retry_at() {
+        let now = Utc::now();
+        let retry = RetryConfig::default();
+        assert_eq!(retry.retry_at(1, now), Some(now + retry.initial_delay));
+        assert_eq!(retry.retry_at(retry.max_attempts, now), None);
+        let once = RetryConfig {
+            max_attempts: 1,
+            ..Default::default()
+        };
+        assert_eq!(once.retry_at(1, now), None);
+    }
+
+    #[test]
+    fn retention_keep_last() {
+        let retention: Retention = serde_yaml::from_str("keep_last: 2").unwrap();
+        let now = Utc::now();
+        assert_eq!(retention.check(0, now, 0, now), None);
+        assert_eq!(retention.check(1, now, 0, now), None);
+        assert!(retention.check(2, now, 0, now).is_some());
+    }
+
+    #[test]
+    fn retention_max_age() {
+        let retention: Retention = serde_yaml::from_str("max_age: 7d").unwrap();
+        let now = Utc::now();
+        assert_eq!(retention.check(10, now - Duration::days(7), 0, now), None);
+        assert!(retention
+            .check(0, now - Duration::days(7) - Duration::seconds(1), 0, now)
+            .is_some());
+    }
+
+    #[test]
+    fn retention_max_size() {
+        let retention: Retention = serde_yaml::from_str("max_size: 1G").unwrap();
+        let now = Utc::now();
+        assert_eq!(retention.check(0, now, 1 << 30, now), None);
+        assert!(retention.check(0, now, (1 << 30) + 1, now).is_some());
+    }
+
+    #[test]
+    fn retention_without_rules_keeps_everything() {
+        let now = Utc::now();
+        let old = now - Duration::days(10_000);
+        assert_eq!(Retention::default().check(1000, old, u64::MAX, now), None);
+    }
+}
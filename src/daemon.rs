@@ -1,64 +1,255 @@
 use crate::{
-    config::Config,
+    config::{Config, Dirs, MissingFiles},
     db::Database,
-    dl,
+    dl::{self, Crawler, DownloadResult, DownloadTask, FeedResult},
     error::Error,
-    gui,
-    media::{Media, MediaEntry},
-    source::{Source, Sources},
+    events::{Event, Events},
+    gui::WebServer,
+    media::{DownloadLog, Failure, Media, MediaEntry},
+    schedule::Scheduler,
+    source::{Source, SourceType, Sources},
 };
-use crossbeam_channel::{unbounded, Receiver};
-use notify::{Event, INotifyWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use chrono::{DateTime, Local, TimeZone, Utc};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     fs,
+    future::{self, Future},
+    io,
     path::PathBuf,
     sync::Arc,
-    thread::{self, JoinHandle},
-    time::{Duration, Instant},
 };
-use tokio::{sync::Mutex, task::JoinHandle as Task};
-use tracing::{error, info};
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex,
+    },
+    time::{self, Instant},
+};
+use tracing::{error, info, warn};
+
+/// Interval in seconds at which the free disk space is checked again when it is too low
+const DISK_SPACE_CHECK_INTERVAL: i64 = 60;
+/// Interval at which old downloads are deleted according to the retention rules
+const RETENTION_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3600);
+/// Smallest number of newly missing files for which reconciling is stopped if more than half of
+/// the library went missing at once
+const MISSING_FILES_THRESHOLD: usize = 3;
+
+/// Commands sent from the web API to the daemon
+pub enum Command {
+    /// The config or the download queue was changed
+    Update,
+    /// Check the library for downloads whose files were removed
+    Reconcile,
+}
+
+/// What a sync would do with an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Download,
+    Skip,
+}
 
-const UPDATE_INTERVAL: u64 = 500;
-const DIR_NAME: &str = "drainpipe";
+#[derive(Serialize)]
+pub struct PreviewEntry {
+    #[serde(flatten)]
+    pub entry: MediaEntry,
+    pub verdict: Verdict,
+    /// Why the entry is skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Crawls all sources and returns what a sync would do with each entry, without queueing anything
+/// or updating the sync state of the sources
+pub async fn preview_sync(
+    state: &Mutex<State>,
+    db: &Database,
+    crawler: &Crawler,
+) -> Result<Vec<PreviewEntry>, Error> {
+    let (sources, config) = {
+        let state = state.lock().await;
+        let sources = state.sources.get().into_iter().filter(|s| !s.paused);
+        (sources.collect(), state.config.data.crawl.clone())
+    };
+    // Without the cached validators every feed is fetched in full
+    let results = crawler.crawl(sources, HashMap::new(), &config).await;
+    for result in &results {
+        if let Some(e) = &result.error {
+            warn!("Failed to sync {}: {e}", result.url);
+        }
+    }
+    let mut previews: Vec<PreviewEntry> = Vec::new();
+    for entry in results.into_iter().flat_map(|r| r.entries) {
+        let mut reason = state.lock().await.skip_reason(&entry);
+        if reason.is_none()
+            && previews
+                .iter()
+                .any(|p| p.entry == entry && p.verdict == Verdict::Download)
+        {
+            reason = Some("duplicate entry".to_string());
+        }
+        if reason.is_none() {
+            reason = db.exclusion(&entry.link).await?;
+        }
+        previews.push(PreviewEntry {
+            entry,
+            verdict: match reason {
+                Some(_) => Verdict::Skip,
+                None => Verdict::Download,
+            },
+            reason,
+        });
+    }
+    Ok(previews)
+}
+
+/// Parts of the daemon that run
+pub struct Options {
+    /// Serve the web interface and API
+    pub web: bool,
+    /// Start downloads, otherwise new entries are only added to the queue
+    pub downloads: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            web: true,
+            downloads: true,
+        }
+    }
+}
+
+/// What the daemon is currently not doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Pause {
+    #[default]
+    None,
+    /// Keep syncing and queueing, but don't start new downloads
+    Downloads,
+    /// Don't sync and don't start new downloads
+    All,
+}
 
 pub struct State {
     pub config: Config,
     pub sources: Sources,
+    pub paused: Pause,
+    pub scheduler: Scheduler,
+    /// Free space in the media directory at the last check
+    pub free_space: Option<u64>,
+    /// Downloads are stopped because the free space is below the minimum
+    pub low_disk_space: bool,
+    /// Downloads are stopped because the free space could not be checked
+    pub disk_space_error: Option<String>,
     pub dl_queue: VecDeque<MediaEntry>,
-    pub dl_tasks: Vec<(MediaEntry, JoinHandle<Result<Media, String>>)>,
+    pub dl_tasks: Vec<DownloadTask>,
+    pub events: Events,
+}
+
+impl State {
+    /// Returns why an entry from a sync is not queued, as far as the state can tell: it is
+    /// filtered out, already queued or being downloaded
+    pub fn skip_reason(&self, entry: &MediaEntry) -> Option<String> {
+        if let Some(reason) = self
+            .config
+            .data
+            .download_filter
+            .as_ref()
+            .and_then(|f| f.filter(entry))
+        {
+            return Some(format!("filtered: {reason}"));
+        }
+        if self.dl_queue.contains(entry) {
+            return Some("already queued".to_string());
+        }
+        if self.dl_tasks.iter().any(|t| &t.entry == entry) {
+            return Some("already downloading".to_string());
+        }
+        None
+    }
+
+    /// Returns the part of the global rate limit that is not used by running downloads, `None` if
+    /// there is no global rate limit
+    fn rate_budget(&self) -> Option<u64> {
+        let used: u64 = self.dl_tasks.iter().filter_map(|t| t.rate_limit).sum();
+        self.config.data.rate_limit.map(|r| r.saturating_sub(used))
+    }
+
+    /// Removes an entry from the download queue or from the running downloads. The task of a
+    /// running download is returned, so it can be killed without holding the state.
+    pub fn cancel(
+        &mut self,
+        link: Option<&str>,
+        id: Option<i64>,
+    ) -> Option<(MediaEntry, Option<DownloadTask>)> {
+        let matches =
+            |e: &MediaEntry| Some(e.link.as_str()) == link || (id.is_some() && e.id == id);
+        if let Some(n) = self.dl_tasks.iter().position(|t| matches(&t.entry)) {
+            let task = self.dl_tasks.remove(n);
+            info!("Cancelling download of '{}'", task.entry.link);
+            Some((task.entry.clone(), Some(task)))
+        } else {
+            let n = self.dl_queue.iter().position(matches)?;
+            let entry = self.dl_queue.remove(n)?;
+            info!("Removed '{}' from download queue", entry.link);
+            Some((entry, None))
+        }
+    }
 }
 
 pub struct Daemon {
     config_path: PathBuf,
     sources_path: PathBuf,
-    fs_event_rx: Receiver<Result<Event, notify::Error>>,
-    _watcher: INotifyWatcher,
-    sync_task: Option<Task<Vec<MediaEntry>>>,
-    last_sync: Option<Instant>,
+    fs_event_rx: UnboundedReceiver<Result<notify::Event, notify::Error>>,
+    _watcher: RecommendedWatcher,
+    cmd_rx: UnboundedReceiver<Command>,
+    dl_tx: UnboundedSender<DownloadResult>,
+    dl_rx: UnboundedReceiver<DownloadResult>,
+    sync_tx: UnboundedSender<Vec<FeedResult>>,
+    sync_rx: UnboundedReceiver<Vec<FeedResult>>,
+    syncing: bool,
+    last_reconcile: DateTime<Utc>,
+    crawler: Arc<Crawler>,
+    server: Option<WebServer>,
+    downloads: bool,
     state: Arc<Mutex<State>>,
     db: Database,
 }
 
 impl Daemon {
-    pub async fn start() -> Result<Self, Error> {
-        let config_dir = dirs::config_dir().unwrap().join(DIR_NAME);
-        fs::create_dir_all(&config_dir)?;
-
-        let config_path = config_dir.join("config.yaml");
-        let sources_path = config_dir.join("sources.yaml");
+    pub async fn start(dirs: &Dirs, options: Options) -> Result<Self, Error> {
+        dirs.create()?;
+        let config_path = dirs.config_path();
+        let sources_path = dirs.sources_path();
         let config = Config::load(&config_path)?;
         let sources = Sources::load(&sources_path)?;
-        let state = State {
+        let mut state = State {
             config,
             sources,
+            paused: Pause::None,
+            scheduler: Scheduler::default(),
+            free_space: None,
+            low_disk_space: false,
+            disk_space_error: None,
             dl_queue: VecDeque::new(),
             dl_tasks: Vec::new(),
+            events: Events::default(),
         };
 
-        let (event_tx, event_rx) = unbounded();
-        let mut watcher = RecommendedWatcher::new(event_tx, notify::Config::default())?;
+        let (fs_event_tx, fs_event_rx) = unbounded_channel();
+        let mut watcher = RecommendedWatcher::new(
+            move |event| {
+                fs_event_tx.send(event).ok();
+            },
+            notify::Config::default(),
+        )?;
         if !state.config.from_env {
             watcher.watch(&config_path, RecursiveMode::NonRecursive)?;
         }
@@ -66,122 +257,735 @@ impl Daemon {
 
         fs::create_dir_all(&state.config.data.media_dir)?;
 
-        let data_dir = dirs::data_dir().unwrap().join(DIR_NAME);
-        fs::create_dir_all(&data_dir)?;
-        let db = Database::load(&data_dir.join("library.db")).await?;
+        let db = Database::load(&dirs.database_path()).await?;
+        state.paused = db.get_paused().await?;
+        if state.paused != Pause::None {
+            info!("Daemon is paused ({:?})", state.paused);
+        }
+        state.scheduler = Scheduler::new(db.get_source_syncs().await?, &state.config.data);
+        state.dl_queue = db.get_queue().await?.into();
+        if !state.dl_queue.is_empty() {
+            info!(
+                "Restored {} entries to download queue",
+                state.dl_queue.len()
+            );
+        }
 
-        let port = state.config.data.port;
+        let addr = state.config.data.web_address();
         let state = Arc::new(Mutex::new(state));
-        gui::start(port, Arc::new(db.clone()), state.clone());
+        let (cmd_tx, cmd_rx) = unbounded_channel();
+        let crawler = Arc::new(Crawler::default());
+        let server = if options.web {
+            Some(WebServer::start(
+                addr,
+                Arc::new(db.clone()),
+                state.clone(),
+                cmd_tx,
+                crawler.clone(),
+            )?)
+        } else {
+            None
+        };
 
+        let (dl_tx, dl_rx) = unbounded_channel();
+        let (sync_tx, sync_rx) = unbounded_channel();
         Ok(Self {
             config_path,
             sources_path,
-            fs_event_rx: event_rx,
+            fs_event_rx,
             _watcher: watcher,
-            last_sync: None,
-            sync_task: None,
+            cmd_rx,
+            dl_tx,
+            dl_rx,
+            sync_tx,
+            sync_rx,
+            syncing: false,
+            // Reconcile right after starting
+            last_reconcile: DateTime::<Utc>::MIN_UTC,
+            crawler,
+            server,
+            downloads: options.downloads,
             state,
             db,
         })
     }
 
-    pub async fn run(mut self) -> Result<(), Error> {
+    /// Runs the daemon until the `shutdown` future completes
+    pub async fn run(mut self, shutdown: impl Future<Output = ()>) -> Result<(), Error> {
         info!("Daemon started.");
+        tokio::pin!(shutdown);
+        let mut retention_timer = time::interval(RETENTION_INTERVAL);
         loop {
-            let state = self.state.clone();
-            let mut state = state.lock().await;
-
-            // Receive finished threads
-            let mut finished = Vec::with_capacity(state.dl_tasks.len());
-            for (n, (_, thread)) in state.dl_tasks.iter().enumerate() {
-                if thread.is_finished() {
-                    finished.push(n);
-                }
-            }
-            for n in finished.into_iter().rev() {
-                let (_, thread) = state.dl_tasks.remove(n);
-                match thread.join().unwrap() {
-                    Ok(media) => {
-                        info!("Downloaded '{}' to '{}'", media.title, media.path);
-                        self.db.insert(&media).await?;
+            let next_sync = self.next_sync().await;
+            let next_download = self.next_download().await;
+            let next_reconcile = self.next_reconcile().await;
+            let paused = self.state.lock().await.paused;
+            tokio::select! {
+                Some((entry, result, log)) = self.dl_rx.recv() => {
+                    self.finish_download(entry, result, log).await?;
+                }
+                Some(event) = self.fs_event_rx.recv() => {
+                    self.handle_fs_event(event?).await?;
+                }
+                Some(results) = self.sync_rx.recv() => {
+                    self.finish_sync(results).await?;
+                }
+                Some(cmd) = self.cmd_rx.recv() => {
+                    self.handle_command(cmd).await?;
+                }
+                _ = sleep_until(next_sync), if !self.syncing && paused != Pause::All => {
+                    self.start_sync(false).await?;
+                }
+                _ = sleep_until(next_reconcile) => {
+                    self.reconcile().await?;
+                }
+                _ = retention_timer.tick() => {
+                    self.apply_retention().await?;
+                    self.prune_download_logs().await?;
+                }
+                // Wake up to start downloads that are due for a retry or in a new download window
+                _ = sleep_until(next_download) => {}
+                _ = &mut shutdown => break,
+            }
+            if let Some(server) = &mut self.server {
+                let addr = self.state.lock().await.config.data.web_address();
+                server.rebind(addr).await;
+            }
+            self.start_downloads().await?;
+        }
+        self.shutdown().await
+    }
+
+    /// Syncs all sources once and adds the new entries to the download queue, returns the number
+    /// of sources that failed to sync
+    pub async fn sync_once(mut self) -> Result<usize, Error> {
+        let failed = self.sync_all().await?;
+        self.shutdown().await?;
+        Ok(failed)
+    }
+
+    /// Syncs all sources once and downloads everything that is ready in the download queue, then
+    /// stops. Returns the number of sources and downloads that failed, including ready downloads
+    /// that could not start because downloads are paused, outside the download windows or there
+    /// is not enough disk space. Fails if it is interrupted by the shutdown signal.
+    pub async fn run_once(mut self, shutdown: impl Future<Output = ()>) -> Result<usize, Error> {
+        tokio::pin!(shutdown);
+        let mut failed = tokio::select! {
+            failed = self.sync_all() => failed?,
+            _ = &mut shutdown => {
+                self.shutdown().await?;
+                return Err(interrupted());
+            }
+        };
+        loop {
+            self.start_downloads().await?;
+            if self.state.lock().await.dl_tasks.is_empty() {
+                break;
+            }
+            tokio::select! {
+                Some((entry, result, log)) = self.dl_rx.recv() => {
+                    if result.is_err() {
+                        failed += 1;
                     }
-                    Err(e) => error!("Download failed: {e}"),
+                    self.finish_download(entry, result, log).await?;
+                }
+                _ = &mut shutdown => {
+                    self.shutdown().await?;
+                    return Err(interrupted());
                 }
             }
+        }
+        let state = self.state.lock().await;
+        let left = state.dl_queue.len();
+        if left > 0 {
+            info!("{left} entries are left in the download queue");
+        }
+        // Nothing is running anymore, so ready entries are held back by one of the checks
+        let now = Utc::now();
+        let blocked = state.dl_queue.iter().filter(|e| e.is_ready(now)).count();
+        if blocked > 0 {
+            let reason = if state.paused != Pause::None {
+                "downloads are paused"
+            } else if state.low_disk_space || state.disk_space_error.is_some() {
+                "there is not enough free disk space"
+            } else {
+                "it is outside the download windows"
+            };
+            warn!("{blocked} downloads could not start because {reason}");
+            failed += blocked;
+        }
+        drop(state);
+        self.shutdown().await?;
+        Ok(failed)
+    }
 
-            // Receive file updates
-            if let Ok(event) = self.fs_event_rx.try_recv() {
-                let event = event?;
-                if !event.kind.is_access() {
-                    if event.paths.contains(&self.config_path) {
-                        state.config.reload()?;
-                    } else if event.paths.contains(&self.sources_path) {
-                        state.sources.reload()?;
+    /// Returns what a sync of all sources would do, without changing anything
+    pub async fn preview_sync(self) -> Result<Vec<PreviewEntry>, Error> {
+        let previews = preview_sync(&self.state, &self.db, &self.crawler).await?;
+        self.shutdown().await?;
+        Ok(previews)
+    }
+
+    /// Syncs all sources that are not paused, returns the number of sources that failed to sync
+    async fn sync_all(&mut self) -> Result<usize, Error> {
+        self.start_sync(true).await?;
+        let results = self
+            .sync_rx
+            .recv()
+            .await
+            .ok_or_else(|| Error::Custom("Sync was aborted".to_string()))?;
+        let failed = results.iter().filter(|r| r.error.is_some()).count();
+        self.finish_sync(results).await?;
+        Ok(failed)
+    }
+
+    /// Waits for running downloads to finish within the shutdown timeout, then kills the remaining
+    /// ones. Killed downloads stay in the persistent queue and are restarted on the next start.
+    async fn shutdown(mut self) -> Result<(), Error> {
+        let timeout = self.state.lock().await.config.data.shutdown_timeout;
+        let running = self.state.lock().await.dl_tasks.len();
+        if running > 0 {
+            info!(
+                "Shutting down, waiting up to {}s for {running} downloads..",
+                timeout.num_seconds()
+            );
+            let deadline = Instant::now() + timeout.to_std().unwrap_or_default();
+            while !self.state.lock().await.dl_tasks.is_empty() {
+                tokio::select! {
+                    Some((entry, result, log)) = self.dl_rx.recv() => {
+                        self.finish_download(entry, result, log).await?;
                     }
+                    _ = time::sleep_until(deadline) => break,
+                }
+            }
+            let tasks: Vec<DownloadTask> = self.state.lock().await.dl_tasks.drain(..).collect();
+            for task in tasks {
+                info!("Killing download of '{}'", task.entry.link);
+                task.kill().await;
+            }
+        } else {
+            info!("Shutting down..");
+        }
+        self.db.close().await;
+        info!("Daemon stopped.");
+        Ok(())
+    }
+
+    /// Returns the earliest time any of the sources is due for a sync
+    async fn next_sync(&self) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().await;
+        state
+            .scheduler
+            .next(state.sources.as_slice(), &state.config.data)
+    }
+
+    async fn next_reconcile(&self) -> Option<DateTime<Utc>> {
+        let interval = self.state.lock().await.config.data.reconcile_interval;
+        if interval <= chrono::Duration::zero() {
+            return None;
+        }
+        self.last_reconcile.checked_add_signed(interval)
+    }
+
+    /// Returns the next time more downloads may be started: the earliest retry time of the
+    /// entries in the download queue, the start of the next download window or the next disk
+    /// space check
+    async fn next_download(&self) -> Option<DateTime<Utc>> {
+        let state = self.state.lock().await;
+        // Resuming sends a command, which wakes up the loop
+        if !self.downloads || state.paused != Pause::None {
+            return None;
+        }
+        let now = Utc::now();
+        if state.low_disk_space || state.disk_space_error.is_some() {
+            return Some(now + chrono::Duration::seconds(DISK_SPACE_CHECK_INTERVAL));
+        }
+        // Retries only matter when a slot and some of the rate limit are free, a finished download
+        // wakes up the loop anyway
+        let limit = state.config.data.download_limit(now.with_timezone(&Local));
+        let next_retry = if (state.dl_tasks.len() as u64) < limit && state.rate_budget() != Some(0)
+        {
+            state
+                .dl_queue
+                .iter()
+                .filter_map(|e| e.failure.as_ref()?.retry_at)
+                .min()
+        } else {
+            None
+        };
+        let next_window = state
+            .config
+            .data
+            .next_download_window(now.with_timezone(&Local))
+            .map(|t| t.with_timezone(&Utc));
+        // Never wake up right away, that would keep the loop spinning
+        next_retry
+            .into_iter()
+            .chain(next_window)
+            .min()
+            .map(|t| t.max(now + chrono::Duration::seconds(1)))
+    }
+
+    async fn handle_command(&mut self, cmd: Command) -> Result<(), Error> {
+        match cmd {
+            // Nothing to do here, the loop picks up the changes on the next iteration
+            Command::Update => {}
+            Command::Reconcile => self.reconcile().await?,
+        }
+        Ok(())
+    }
+
+    async fn handle_fs_event(&mut self, event: notify::Event) -> Result<(), Error> {
+        if !event.kind.is_access() {
+            let mut state = self.state.lock().await;
+            if event.paths.contains(&self.config_path) {
+                state.config.reload()?;
+                state.events.send(Event::ConfigReloaded);
+            } else if event.paths.contains(&self.sources_path) {
+                state.sources.reload()?;
+                state.events.send(Event::SourcesReloaded);
+            }
+        }
+        Ok(())
+    }
+
+    async fn finish_download(
+        &mut self,
+        entry: MediaEntry,
+        result: Result<Media, String>,
+        log: Option<DownloadLog>,
+    ) -> Result<(), Error> {
+        let retry = {
+            let mut state = self.state.lock().await;
+            // The download was cancelled after it finished, but before its result was handled
+            let Some(n) = state.dl_tasks.iter().position(|t| t.entry == entry) else {
+                info!("Ignoring result of cancelled download '{}'", entry.link);
+                return Ok(());
+            };
+            state.dl_tasks.remove(n);
+            state.config.data.retry.clone()
+        };
+        if let Some(log) = &log {
+            self.db.insert_download_log(&entry.link, log).await?;
+        }
+        match result {
+            Ok(media) => {
+                info!("Downloaded '{}' to '{}'", media.title, media.path);
+                self.db.insert(&media).await?;
+                self.db.queue_remove(&entry.link).await?;
+                let state = self.state.lock().await;
+                state.events.send(Event::DownloadCompleted { entry, media });
+            }
+            Err(e) => {
+                let attempts = entry.failure.as_ref().map(|f| f.attempts).unwrap_or(0) + 1;
+                let retry_at = retry.retry_at(attempts, Utc::now());
+                if let Some(retry_at) = retry_at {
+                    error!(
+                        "Download of '{}' failed (attempt {attempts}), retrying at {retry_at}: {e}",
+                        entry.link
+                    );
+                } else {
+                    error!(
+                        "Download of '{}' failed permanently after {attempts} attempts: {e}",
+                        entry.link
+                    );
+                }
+                let failure = Failure {
+                    attempts,
+                    error: e,
+                    retry_at,
+                };
+                self.db.queue_fail(&entry.link, &failure).await?;
+                let mut state = self.state.lock().await;
+                state.events.send(Event::DownloadFailed {
+                    entry: entry.clone(),
+                    error: failure.error.clone(),
+                    retry_at,
+                });
+                if retry_at.is_some() {
+                    state.dl_queue.push_back(MediaEntry {
+                        failure: Some(failure),
+                        ..entry
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs the sources that are due, or all sources that are not paused if `all` is set
+    async fn start_sync(&mut self, all: bool) -> Result<(), Error> {
+        let now = Utc::now();
+        let mut guard = self.state.lock().await;
+        let state = &mut *guard;
+        let sources = if all {
+            state
+                .sources
+                .get()
+                .into_iter()
+                .filter(|s| !s.paused)
+                .collect()
+        } else {
+            state
+                .scheduler
+                .due(state.sources.get(), &state.config.data, now)
+        };
+        info!("Starting sync of {} sources..", sources.len());
+        state.events.send(Event::SyncStarted {
+            sources: sources.iter().map(|s| s.url.clone()).collect(),
+        });
+        for source in &sources {
+            state.scheduler.synced(&source.url, now, &state.config.data);
+        }
+        let keys = feed_cache_keys(state);
+        let config = state.config.data.crawl.clone();
+        drop(guard);
+        for source in &sources {
+            self.db.set_source_synced(&source.url, now).await?;
+        }
+        let caches = self.db.get_feed_caches(&keys).await?;
+        let crawler = self.crawler.clone();
+        let sync_tx = self.sync_tx.clone();
+        tokio::spawn(async move {
+            sync_tx
+                .send(crawler.crawl(sources, caches, &config).await)
+                .ok();
+        });
+        self.syncing = true;
+        Ok(())
+    }
+
+    async fn finish_sync(&mut self, results: Vec<FeedResult>) -> Result<(), Error> {
+        self.syncing = false;
+        let mut entries = Vec::new();
+        for result in &results {
+            entries.extend(result.entries.iter().cloned());
+        }
+        info!("Got {} entries from sync", entries.len());
+        // Apply download filter and check if not already in queue and not already being
+        // downloaded
+        {
+            let state = self.state.lock().await;
+            entries.retain(|e| state.skip_reason(e).is_none());
+        }
+        info!("Filtered {}", entries.len());
+        // Check if not already downloaded, permanently failed or blocked, without holding the
+        // state lock
+        let mut queued: Vec<MediaEntry> = Vec::with_capacity(entries.len());
+        for mut e in entries {
+            if !queued.contains(&e) && self.db.exclusion(&e.link).await?.is_none() {
+                e.id = Some(self.db.queue_push(&e).await?);
+                info!("Added '{}' to download queue", e.link);
+                queued.push(e);
+            }
+        }
+        for result in &results {
+            let count = queued
+                .iter()
+                .filter(|e| e.source.as_ref() == Some(&result.url))
+                .count();
+            self.db.insert_sync_run(result, count).await?;
+            let failures = self.db.update_source_health(result).await?;
+            self.check_source_health(&result.url, failures).await?;
+        }
+        let mut state = self.state.lock().await;
+        state.events.send(Event::SyncFinished {
+            entries: results.iter().map(|r| r.entries.len()).sum(),
+            queued: queued.len(),
+            failed: results.iter().filter(|r| r.error.is_some()).count(),
+        });
+        if !queued.is_empty() {
+            state.events.send(Event::Queued {
+                entries: queued.clone(),
+            });
+        }
+        state.dl_queue.extend(queued);
+        let keys = feed_cache_keys(&state);
+        drop(state);
+        // The fetched versions are only remembered once their entries are queued, the next sync
+        // would not get them again
+        for result in &results {
+            if let (Some(cache), Some(key)) = (&result.cache, keys.get(&result.url)) {
+                self.db.set_feed_cache(&result.url, cache, key).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pauses a source once its number of consecutive failed syncs reaches the threshold
+    async fn check_source_health(&self, url: &str, failures: u32) -> Result<(), Error> {
+        if failures == 0 {
+            return Ok(());
+        }
+        let mut state = self.state.lock().await;
+        let disable_after = state.config.data.disable_after;
+        if disable_after == 0 || failures < disable_after {
+            if failures == state.config.data.unhealthy_after {
+                warn!("Source '{url}' is unhealthy, the last {failures} syncs failed");
+            }
+            return Ok(());
+        }
+        let mut sources = state.sources.get();
+        if let Some(source) = sources.iter_mut().find(|s| s.url == url && !s.paused) {
+            warn!("Pausing source '{url}' after {failures} failed syncs");
+            source.paused = true;
+            state.sources.set(sources)?;
+        }
+        Ok(())
+    }
+
+    /// Checks whether the files of all downloads still exist. Downloads whose files were removed
+    /// are marked as missing or downloaded again, depending on the config.
+    async fn reconcile(&mut self) -> Result<(), Error> {
+        self.last_reconcile = Utc::now();
+        let (media_dir, action, sources) = {
+            let state = self.state.lock().await;
+            let config = &state.config.data;
+            (
+                config.media_dir.clone(),
+                config.missing_files,
+                state.sources.get(),
+            )
+        };
+        // An unmounted media directory would make every file look missing
+        if !media_dir.is_dir() {
+            warn!("Media directory {media_dir:?} does not exist, not checking for missing files");
+            return Ok(());
+        }
+        info!("Checking the library for missing files..");
+        let library = self.db.get_all().await?;
+        let total = library.len();
+        // Checking every file takes a while on large libraries
+        let (found, missing): (Vec<_>, Vec<_>) = {
+            let media_dir = media_dir.clone();
+            tokio::task::spawn_blocking(move || {
+                library
+                    .into_iter()
+                    .partition(|m| media_dir.join(&m.path).exists())
+            })
+            .await
+            .map_err(|e| Error::Custom(e.to_string()))?
+        };
+        for media in found.into_iter().filter(|m| m.missing) {
+            info!("Found '{}' again", media.path);
+            self.db.set_missing(&media.source, false).await?;
+        }
+        // Files disappearing all at once are more likely on a disk that is not mounted than
+        // removed one by one
+        let newly_missing = missing.iter().filter(|m| !m.missing).count();
+        if newly_missing >= MISSING_FILES_THRESHOLD && newly_missing * 2 > total {
+            warn!(
+                "Files of {newly_missing} of {total} downloads are missing, not changing the \
+                library. Check that {media_dir:?} is available."
+            );
+            return Ok(());
+        }
+        let mut requeued = Vec::new();
+        for media in missing {
+            match action {
+                MissingFiles::Mark if !media.missing => {
+                    warn!("File of '{}' is missing: '{}'", media.title, media.path);
+                    self.db.set_missing(&media.source, true).await?;
+                }
+                MissingFiles::Mark => {}
+                MissingFiles::Requeue => {
+                    let r#type = sources
+                        .iter()
+                        .find(|s| Some(&s.url) == media.feed.as_ref())
+                        .map(|s| s.r#type.clone())
+                        .unwrap_or(SourceType::Video);
+                    let mut entry = MediaEntry {
+                        id: None,
+                        title: Some(media.title),
+                        link: media.source,
+                        published: None,
+                        r#type,
+                        source: media.feed,
+                        failure: None,
+                    };
+                    self.db.remove(&entry.link).await?;
+                    entry.id = Some(self.db.queue_push(&entry).await?);
+                    info!(
+                        "File of '{}' is missing, added it to download queue",
+                        entry.link
+                    );
+                    requeued.push(entry);
                 }
             }
+        }
+        let mut state = self.state.lock().await;
+        if !requeued.is_empty() {
+            state.events.send(Event::Queued {
+                entries: requeued.clone(),
+            });
+        }
+        state.dl_queue.extend(requeued);
+        Ok(())
+    }
 
-            // Receive sync
-            if let Some(task) = &self.sync_task {
-                if task.is_finished() {
-                    let task = self.sync_task.take().unwrap();
-                    match task.await {
-                        Ok(mut entries) => {
-                            info!("Got {} entries from sync", entries.len());
-                            // Apply download filter
-                            if let Some(filter) = &state.config.data.download_filter {
-                                entries.retain(|e| !filter.filter(e));
-                            }
-                            info!("Filtered {}", entries.len());
-                            // Check if not alreaday downloaded
-                            for e in entries {
-                                // TODO: Prevent blocking here
-                                // Check if not already in queue, not already being downloaded and
-                                // not already downloaded
-                                if !state.dl_queue.contains(&e)
-                                    && !state.dl_tasks.iter().any(|(e2, _)| e2.link == e.link)
-                                    && self.db.get(&e.link).await?.is_none()
-                                {
-                                    info!("Added '{}' to download queue", e.link);
-                                    state.dl_queue.push_back(e);
-                                }
-                            }
-                        }
-                        Err(e) => error!("Failed sync: {e}"),
+    /// Deletes the downloads that are no longer kept by the retention rules of their source
+    async fn apply_retention(&self) -> Result<(), Error> {
+        let (sources, config) = {
+            let state = self.state.lock().await;
+            (state.sources.get(), state.config.data.clone())
+        };
+        let now = Utc::now();
+        for source in sources {
+            let Some(retention) = source.retention.as_ref().or(config.retention.as_ref()) else {
+                continue;
+            };
+            let mut total_size = 0;
+            for (i, media) in self.db.get_by_feed(&source.url).await?.iter().enumerate() {
+                let path = config.media_dir.join(&media.path);
+                total_size += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                let date = Utc.timestamp_opt(media.date, 0).single().unwrap_or(now);
+                let Some(reason) = retention.check(i, date, total_size, now) else {
+                    continue;
+                };
+                match fs::remove_file(&path) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        warn!(
+                            "File of '{}' was already removed: '{}'",
+                            media.title,
+                            path.display()
+                        );
+                    }
+                    Err(e) => {
+                        error!("Failed to delete '{}': {e}", path.display());
+                        continue;
                     }
                 }
-            } else if self
-                .last_sync
-                .map(|v| v.elapsed().as_secs() > state.config.data.sync_interval)
-                .unwrap_or(true)
-                || state.sources.changed()
-            {
-                info!("Sarting sync..");
-                self.start_sync(state.sources.get());
+                self.db.set_deleted(&media.source).await?;
+                info!("Deleted '{}' ({}): {reason}", media.title, path.display());
             }
+        }
+        Ok(())
+    }
 
-            // Start downloads
-            for _ in 0..state
-                .config
-                .data
-                .parallel_downloads
-                .saturating_sub(state.dl_tasks.len() as u64)
-            {
-                if let Some(entry) = state.dl_queue.pop_front() {
-                    info!("Start download of {:?}  {}", &entry.title, &entry.link);
-                    let dir = state.config.data.media_dir.to_string_lossy().to_string();
-                    state
-                        .dl_tasks
-                        .push((entry.clone(), dl::download_video(dir, entry)));
+    async fn prune_download_logs(&self) -> Result<(), Error> {
+        let retention = self.state.lock().await.config.data.download_log_retention;
+        self.db.prune_download_logs(Utc::now() - retention).await
+    }
+
+    /// Returns whether there is enough free space in the media directory to start downloads
+    fn check_disk_space(&self, state: &mut State) -> bool {
+        let Some(min_free_space) = state.config.data.min_free_space else {
+            state.low_disk_space = false;
+            state.disk_space_error = None;
+            return true;
+        };
+        let media_dir = &state.config.data.media_dir;
+        let free_space = match fs2::available_space(media_dir) {
+            Ok(free_space) => free_space,
+            Err(e) => {
+                let e = format!("Failed to check the free space in {media_dir:?}: {e}");
+                if state.disk_space_error.as_ref() != Some(&e) {
+                    error!("{e}, not starting new downloads");
                 }
+                state.free_space = None;
+                state.disk_space_error = Some(e);
+                return false;
+            }
+        };
+        if state.disk_space_error.take().is_some() {
+            info!("Checked the free space again, resuming downloads");
+        }
+        let low_disk_space = free_space < min_free_space;
+        if low_disk_space && !state.low_disk_space {
+            warn!("Low disk space ({free_space} bytes free), not starting new downloads");
+        } else if !low_disk_space && state.low_disk_space {
+            info!("Enough disk space again ({free_space} bytes free), resuming downloads");
+        }
+        state.free_space = Some(free_space);
+        state.low_disk_space = low_disk_space;
+        !low_disk_space
+    }
+
+    async fn start_downloads(&mut self) -> Result<(), Error> {
+        let mut state = self.state.lock().await;
+        if !self.downloads || state.paused != Pause::None || !self.check_disk_space(&mut state) {
+            return Ok(());
+        }
+        let now = Utc::now();
+        let limit = state.config.data.download_limit(now.with_timezone(&Local));
+        let free_slots = limit.saturating_sub(state.dl_tasks.len() as u64) as usize;
+        // yt-dlp can't change the limit of a running download, so every download gets a fixed
+        // share of the global rate limit, and never more than the running downloads leave
+        let rate_share = state
+            .config
+            .data
+            .rate_limit
+            .map(|r| (r / limit.max(1)).max(1));
+        let mut budget = state.rate_budget();
+        for _ in 0..free_slots {
+            if budget == Some(0) {
+                break;
+            }
+            let next = state.dl_queue.iter().position(|e| e.is_ready(now));
+            if let Some(entry) = next.and_then(|n| state.dl_queue.remove(n)) {
+                info!("Start download of {:?}  {}", &entry.title, &entry.link);
+                let dir = state.config.data.media_dir.clone();
+                // A source limit can be higher than the share, but never than what is left of the
+                // global limit
+                let rate_limit = state
+                    .sources
+                    .as_slice()
+                    .iter()
+                    .find(|s| Some(&s.url) == entry.source.as_ref())
+                    .and_then(|s| s.rate_limit)
+                    .or(rate_share)
+                    .map(|r| budget.map_or(r, |b| r.min(b)).max(1));
+                budget = budget.map(|b| b - rate_limit.unwrap_or(0));
+                self.db.queue_start(&entry.link).await?;
+                state.events.send(Event::DownloadStarted {
+                    entry: entry.clone(),
+                });
+                let task = dl::download_video(
+                    dir,
+                    entry,
+                    rate_limit,
+                    self.dl_tx.clone(),
+                    state.events.clone(),
+                );
+                state.dl_tasks.push(task);
             }
-            thread::sleep(Duration::from_millis(UPDATE_INTERVAL));
         }
+        Ok(())
     }
+}
+
+fn interrupted() -> Error {
+    Error::Custom("Interrupted before all syncs and downloads finished".to_string())
+}
 
-    fn start_sync(&mut self, sources: Vec<Source>) {
-        self.sync_task = Some(tokio::spawn(dl::crawl_sources(sources)));
-        self.last_sync = Some(Instant::now());
+/// Returns the cache key of each source. It changes with the config of the source and the download
+/// filter, so feeds are fetched in full again when different entries could be queued.
+fn feed_cache_keys(state: &State) -> HashMap<String, String> {
+    let filter = &state.config.data.download_filter;
+    state
+        .sources
+        .as_slice()
+        .iter()
+        .map(|source| {
+            // Pausing a source does not change its entries
+            let source = Source {
+                paused: false,
+                ..source.clone()
+            };
+            let key = serde_json::to_string(&(&source, filter)).unwrap_or_default();
+            (source.url, key)
+        })
+        .collect()
+}
+
+/// Sleeps until the given time, or forever if there is none
+async fn sleep_until(time: Option<DateTime<Utc>>) {
+    match time {
+        Some(time) => {
+            let duration = (time - Utc::now()).to_std().unwrap_or_default();
+            time::sleep_until(Instant::now() + duration).await
+        }
+        None => future::pending().await,
     }
 }
@@ -1,8 +1,82 @@
-use crate::{error::Error, media::Media};
-use sqlx::{sqlite::SqliteConnectOptions, FromRow, SqlitePool};
-use std::path::Path;
+use crate::{
+    daemon::Pause,
+    dl::{FeedCache, FeedResult},
+    error::Error,
+    media::{DownloadLog, Failure, Media, MediaEntry},
+    source::{SourceHealth, SyncRun},
+};
+use chrono::prelude::*;
+use sqlx::{sqlite::SqliteConnectOptions, FromRow, Row, SqlitePool};
+use std::{collections::HashMap, path::Path};
 use tracing::log::info;
 
+/// Schema migrations, applied in order. The index of the last applied migration is stored in the
+/// `user_version` pragma, so never change or remove existing entries, only append new ones.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS media (
+        source      TEXT PRIMARY KEY NOT NULL,
+        id          TEXT NOT NULL,
+        path        TEXT NOT NULL,
+        title       TEXT,
+        description TEXT,
+        date        INT NOT NULL
+    )",
+    "CREATE TABLE queue (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        link        TEXT UNIQUE NOT NULL,
+        title       TEXT,
+        published   INT,
+        type        TEXT NOT NULL,
+        started     INT NOT NULL DEFAULT 0
+    )",
+    "ALTER TABLE queue ADD COLUMN attempts INT NOT NULL DEFAULT 0;
+    ALTER TABLE queue ADD COLUMN last_error TEXT;
+    ALTER TABLE queue ADD COLUMN next_attempt INT;
+    ALTER TABLE queue ADD COLUMN failed INT NOT NULL DEFAULT 0;",
+    "ALTER TABLE queue ADD COLUMN blocked INT NOT NULL DEFAULT 0",
+    "CREATE TABLE settings (
+        key         TEXT PRIMARY KEY NOT NULL,
+        value       TEXT NOT NULL
+    )",
+    "CREATE TABLE sources (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        url         TEXT UNIQUE NOT NULL,
+        last_sync   INT
+    )",
+    "ALTER TABLE queue ADD COLUMN source TEXT",
+    "ALTER TABLE media ADD COLUMN feed TEXT;
+    ALTER TABLE media ADD COLUMN protected INT NOT NULL DEFAULT 0;
+    ALTER TABLE media ADD COLUMN deleted INT NOT NULL DEFAULT 0;",
+    "ALTER TABLE sources ADD COLUMN etag TEXT;
+    ALTER TABLE sources ADD COLUMN last_modified TEXT;",
+    "CREATE TABLE sync_runs (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        source_id   INT NOT NULL REFERENCES sources(id),
+        time        INT NOT NULL,
+        status      INT,
+        entries     INT NOT NULL,
+        queued      INT NOT NULL,
+        duration    INT NOT NULL,
+        error       TEXT
+    )",
+    "ALTER TABLE sources ADD COLUMN failures INT NOT NULL DEFAULT 0;
+    ALTER TABLE sources ADD COLUMN last_success INT;",
+    "ALTER TABLE media ADD COLUMN missing INT NOT NULL DEFAULT 0",
+    "CREATE TABLE download_logs (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        link        TEXT NOT NULL,
+        time        INT NOT NULL,
+        success     INT NOT NULL,
+        stdout      TEXT NOT NULL,
+        stderr      TEXT NOT NULL
+    );
+    CREATE INDEX download_logs_link ON download_logs(link);",
+    "ALTER TABLE sources ADD COLUMN cache_key TEXT",
+];
+
+/// Number of sync runs kept in the history of each source
+const SYNC_HISTORY_LENGTH: i64 = 100;
+
 #[derive(Clone)]
 pub struct Database {
     pool: SqlitePool,
@@ -18,29 +92,32 @@ impl Database {
         )
         .await?;
 
-        let mut conn = pool.acquire().await?;
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS media (
-                source      TEXT PRIMARY KEY NOT NULL,
-                id          TEXT NOT NULL,
-                path        TEXT NOT NULL,
-                title       TEXT,
-                description TEXT,
-                date        INT NOT NULL
-            )",
-        )
-        .execute(&mut conn)
-        .await?;
+        let mut tx = pool.begin().await?;
+        let version: i64 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&mut tx)
+            .await?;
+        for (n, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            info!("Migrating database to version {}", n + 1);
+            sqlx::query(migration).execute(&mut tx).await?;
+            sqlx::query(&format!("PRAGMA user_version = {}", n + 1))
+                .execute(&mut tx)
+                .await?;
+        }
+        tx.commit().await?;
 
         Ok(Self { pool })
     }
 
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
+
     pub async fn insert(&self, media: &Media) -> Result<(), Error> {
         let mut conn = self.pool.acquire().await?;
         sqlx::query(
             "
-            INSERT INTO media (source, id, path, title, description, date)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            INSERT INTO media (source, id, path, title, description, date, feed)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
         ",
         )
         .bind(&media.source)
@@ -49,6 +126,7 @@ impl Database {
         .bind(&media.title)
         .bind(&media.description)
         .bind(media.date)
+        .bind(&media.feed)
         .execute(&mut conn)
         .await?;
 
@@ -56,7 +134,7 @@ impl Database {
     }
 
     pub async fn get_all(&self) -> Result<Vec<Media>, Error> {
-        let rows = sqlx::query("SELECT * FROM media")
+        let rows = sqlx::query("SELECT * FROM media WHERE deleted=0")
             .fetch_all(&self.pool)
             .await?;
         let mut res = Vec::new();
@@ -82,4 +160,391 @@ impl Database {
             None
         })
     }
+
+    /// Returns the downloads of a source that can be deleted by its retention rules, newest first
+    pub async fn get_by_feed(&self, feed: &str) -> Result<Vec<Media>, Error> {
+        let rows = sqlx::query(
+            "SELECT * FROM media WHERE feed=? AND deleted=0 AND protected=0 ORDER BY date DESC",
+        )
+        .bind(feed)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut res = Vec::new();
+        for row in rows {
+            res.push(Media::from_row(&row)?);
+        }
+        Ok(res)
+    }
+
+    /// Marks the file of a download as deleted. The row is kept, so the entry is not downloaded
+    /// again on the next sync.
+    pub async fn set_deleted(&self, link: &str) -> Result<(), Error> {
+        sqlx::query("UPDATE media SET deleted=1 WHERE source=?")
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_missing(&self, link: &str, missing: bool) -> Result<(), Error> {
+        sqlx::query("UPDATE media SET missing=?1 WHERE source=?2")
+            .bind(missing)
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn remove(&self, link: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM media WHERE source=?")
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Protects a download from retention rules, returns false if there is no such download
+    pub async fn set_protected(&self, link: &str, protected: bool) -> Result<bool, Error> {
+        let res = sqlx::query("UPDATE media SET protected=?1 WHERE source=?2 AND deleted=0")
+            .bind(protected)
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Adds an entry to the end of the persistent download queue and returns its ID
+    pub async fn queue_push(&self, entry: &MediaEntry) -> Result<i64, Error> {
+        sqlx::query(
+            "
+            INSERT OR IGNORE INTO queue (link, title, published, type, source)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+        ",
+        )
+        .bind(&entry.link)
+        .bind(&entry.title)
+        .bind(entry.published.map(|p| p.timestamp()))
+        .bind(&entry.r#type)
+        .bind(&entry.source)
+        .execute(&self.pool)
+        .await?;
+        let id = sqlx::query_scalar("SELECT id FROM queue WHERE link=?")
+            .bind(&entry.link)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(id)
+    }
+
+    /// Marks a queued entry as being downloaded
+    pub async fn queue_start(&self, link: &str) -> Result<(), Error> {
+        sqlx::query("UPDATE queue SET started=1 WHERE link=?")
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn queue_remove(&self, link: &str) -> Result<(), Error> {
+        sqlx::query("DELETE FROM queue WHERE link=?")
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Blocks an entry, so it is no longer part of the queue and is never queued again
+    pub async fn queue_block(&self, link: &str) -> Result<(), Error> {
+        sqlx::query("UPDATE queue SET started=0, blocked=1 WHERE link=?")
+            .bind(link)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Records a failed download attempt. Entries without a retry time are marked as permanently
+    /// failed and are no longer part of the queue.
+    pub async fn queue_fail(&self, link: &str, failure: &Failure) -> Result<(), Error> {
+        sqlx::query(
+            "
+            UPDATE queue
+            SET started=0, attempts=?1, last_error=?2, next_attempt=?3, failed=?4
+            WHERE link=?5
+        ",
+        )
+        .bind(failure.attempts)
+        .bind(&failure.error)
+        .bind(failure.retry_at.map(|t| t.timestamp()))
+        .bind(failure.retry_at.is_none())
+        .bind(link)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the persistent download queue, entries that were being downloaded come first
+    pub async fn get_queue(&self) -> Result<Vec<MediaEntry>, Error> {
+        self.get_entries(
+            "SELECT * FROM queue WHERE failed=0 AND blocked=0 ORDER BY started DESC, id ASC",
+        )
+        .await
+    }
+
+    /// Returns all permanently failed entries
+    pub async fn get_failed(&self) -> Result<Vec<MediaEntry>, Error> {
+        self.get_entries("SELECT * FROM queue WHERE failed=1 ORDER BY id ASC")
+            .await
+    }
+
+    /// Returns why an entry is never queued again: it was downloaded before, has permanently
+    /// failed or is blocked
+    pub async fn exclusion(&self, link: &str) -> Result<Option<String>, Error> {
+        if self.get(link).await?.is_some() {
+            return Ok(Some("already in library".to_string()));
+        }
+        let row: Option<(bool, bool)> = sqlx::query_as(
+            "SELECT failed, blocked FROM queue WHERE link=? AND (failed=1 OR blocked=1)",
+        )
+        .bind(link)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(failed, _)| {
+            if failed {
+                "failed permanently".to_string()
+            } else {
+                "blocked".to_string()
+            }
+        }))
+    }
+
+    async fn get_entries(&self, query: &str) -> Result<Vec<MediaEntry>, Error> {
+        let rows = sqlx::query(query).fetch_all(&self.pool).await?;
+        let mut res = Vec::new();
+        for row in rows {
+            res.push(MediaEntry::from_row(&row)?);
+        }
+        Ok(res)
+    }
+
+    pub async fn get_paused(&self) -> Result<Pause, Error> {
+        let paused = sqlx::query_scalar("SELECT value FROM settings WHERE key='paused'")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(paused.unwrap_or_default())
+    }
+
+    pub async fn set_paused(&self, paused: Pause) -> Result<(), Error> {
+        sqlx::query("INSERT OR REPLACE INTO settings (key, value) VALUES ('paused', ?)")
+            .bind(paused)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the last sync time of every source that was synced before
+    pub async fn get_source_syncs(&self) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
+        let rows: Vec<(String, i64)> =
+            sqlx::query_as("SELECT url, last_sync FROM sources WHERE last_sync IS NOT NULL")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(url, t)| Some((url, Utc.timestamp_opt(t, 0).single()?)))
+            .collect())
+    }
+
+    pub async fn set_source_synced(&self, url: &str, time: DateTime<Utc>) -> Result<(), Error> {
+        sqlx::query(
+            "
+            INSERT INTO sources (url, last_sync) VALUES (?1, ?2)
+            ON CONFLICT (url) DO UPDATE SET last_sync=?2
+        ",
+        )
+        .bind(url)
+        .bind(time.timestamp())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the validators of the feeds whose cache key matches the given one, the others have
+    /// to be fetched in full
+    pub async fn get_feed_caches(
+        &self,
+        keys: &HashMap<String, String>,
+    ) -> Result<HashMap<String, FeedCache>, Error> {
+        let rows = sqlx::query("SELECT * FROM sources WHERE cache_key IS NOT NULL")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut caches = HashMap::new();
+        for row in rows {
+            let url: String = row.try_get("url")?;
+            if keys.get(&url) != Some(&row.try_get("cache_key")?) {
+                continue;
+            }
+            let cache = FeedCache {
+                etag: row.try_get("etag")?,
+                last_modified: row.try_get("last_modified")?,
+            };
+            caches.insert(url, cache);
+        }
+        Ok(caches)
+    }
+
+    /// Stores the validators of a feed, along with a key of the config its entries were queued
+    /// with
+    pub async fn set_feed_cache(
+        &self,
+        url: &str,
+        cache: &FeedCache,
+        key: &str,
+    ) -> Result<(), Error> {
+        sqlx::query(
+            "
+            INSERT INTO sources (url, etag, last_modified, cache_key) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (url) DO UPDATE SET etag=?2, last_modified=?3, cache_key=?4
+        ",
+        )
+        .bind(url)
+        .bind(&cache.etag)
+        .bind(&cache.last_modified)
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the health of all sources that were synced before
+    pub async fn get_source_health(&self) -> Result<HashMap<String, SourceHealth>, Error> {
+        let rows: Vec<(String, i64, u32, Option<i64>)> =
+            sqlx::query_as("SELECT url, id, failures, last_success FROM sources")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(url, id, failures, last_success)| {
+                let health = SourceHealth {
+                    id,
+                    failures,
+                    last_success: last_success.and_then(|t| Utc.timestamp_opt(t, 0).single()),
+                };
+                (url, health)
+            })
+            .collect())
+    }
+
+    /// Updates the health of a source after a sync and returns the number of consecutive failed
+    /// syncs
+    pub async fn update_source_health(&self, run: &FeedResult) -> Result<u32, Error> {
+        let failures = if run.error.is_some() {
+            sqlx::query_scalar(
+                "UPDATE sources SET failures=failures+1 WHERE url=? RETURNING failures",
+            )
+            .bind(&run.url)
+            .fetch_one(&self.pool)
+            .await?
+        } else {
+            sqlx::query("UPDATE sources SET failures=0, last_success=?1 WHERE url=?2")
+                .bind(run.time.timestamp())
+                .bind(&run.url)
+                .execute(&self.pool)
+                .await?;
+            0
+        };
+        Ok(failures)
+    }
+
+    /// Adds a run to the sync history of a source, only the most recent runs are kept
+    pub async fn insert_sync_run(&self, run: &FeedResult, queued: usize) -> Result<(), Error> {
+        let source_id: i64 = sqlx::query_scalar("SELECT id FROM sources WHERE url=?")
+            .bind(&run.url)
+            .fetch_one(&self.pool)
+            .await?;
+        sqlx::query(
+            "
+            INSERT INTO sync_runs (source_id, time, status, entries, queued, duration, error)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        ",
+        )
+        .bind(source_id)
+        .bind(run.time.timestamp())
+        .bind(run.status)
+        .bind(run.entries.len() as i64)
+        .bind(queued as i64)
+        .bind(run.duration.as_millis() as i64)
+        .bind(&run.error)
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "
+            DELETE FROM sync_runs WHERE source_id=?1 AND id NOT IN
+            (SELECT id FROM sync_runs WHERE source_id=?1 ORDER BY id DESC LIMIT ?2)
+        ",
+        )
+        .bind(source_id)
+        .bind(SYNC_HISTORY_LENGTH)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the sync history of a source, most recent first, or `None` if there is no source
+    /// with this ID
+    pub async fn get_sync_runs(&self, source_id: i64) -> Result<Option<Vec<SyncRun>>, Error> {
+        let exists = sqlx::query("SELECT 1 FROM sources WHERE id=?")
+            .bind(source_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        if exists.is_none() {
+            return Ok(None);
+        }
+        let rows = sqlx::query("SELECT * FROM sync_runs WHERE source_id=? ORDER BY id DESC")
+            .bind(source_id)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut res = Vec::new();
+        for row in rows {
+            res.push(SyncRun::from_row(&row)?);
+        }
+        Ok(Some(res))
+    }
+
+    /// Stores the output of a download attempt of the entry with the given link
+    pub async fn insert_download_log(&self, link: &str, log: &DownloadLog) -> Result<(), Error> {
+        sqlx::query(
+            "
+            INSERT INTO download_logs (link, time, success, stdout, stderr)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+        ",
+        )
+        .bind(link)
+        .bind(log.time.timestamp())
+        .bind(log.success)
+        .bind(&log.stdout)
+        .bind(&log.stderr)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the logs of all stored download attempts of an entry, oldest first
+    pub async fn get_download_logs(&self, link: &str) -> Result<Vec<DownloadLog>, Error> {
+        let rows = sqlx::query("SELECT * FROM download_logs WHERE link=? ORDER BY id ASC")
+            .bind(link)
+            .fetch_all(&self.pool)
+            .await?;
+        let mut res = Vec::new();
+        for row in rows {
+            res.push(DownloadLog::from_row(&row)?);
+        }
+        Ok(res)
+    }
+
+    /// Deletes the download logs from before the given time
+    pub async fn prune_download_logs(&self, before: DateTime<Utc>) -> Result<(), Error> {
+        sqlx::query("DELETE FROM download_logs WHERE time<?")
+            .bind(before.timestamp())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
 }
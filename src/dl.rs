@@ -1,54 +1,288 @@
 use crate::{
+    config::CrawlConfig,
     error::Error,
-    media::{Media, MediaEntry},
+    events::{Event, Events},
+    media::{DownloadLog, Media, MediaEntry},
     source::{Source, SourceType},
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use feed_rs::parser;
-use futures::StreamExt;
-use reqwest::Client;
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER},
+    Client, StatusCode, Url,
+};
 use serde::{Deserialize, Serialize};
 use std::{
-    process::Command,
+    collections::HashMap,
+    fs,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::Stdio,
     sync::Arc,
-    thread::{self, JoinHandle},
 };
-use tokio::sync::Mutex;
-use tracing::{debug, error};
-
-pub async fn crawl_sources(sources: Vec<Source>) -> Vec<MediaEntry> {
-    debug!("Crawling feeds from {} sources..", sources.len());
-    let client = Client::new();
-    let items = Arc::new(Mutex::new(Vec::new()));
-    tokio_stream::iter(&sources)
-        .for_each_concurrent(128, |source| {
-            let client = client.clone();
-            let items = items.clone();
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, BufReader},
+    process::Command,
+    sync::{mpsc::UnboundedSender, Mutex, Semaphore},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use tracing::{debug, error, warn};
+
+/// Validators of the last fetched version of a feed, sent along with the next request so the
+/// server can reply that the feed was not modified
+#[derive(Debug, Clone, Default)]
+pub struct FeedCache {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Result of fetching the feed of a source
+pub struct FeedResult {
+    pub url: String,
+    pub time: DateTime<Utc>,
+    /// Time it took to fetch the feed, including waiting for the host limits
+    pub duration: std::time::Duration,
+    /// HTTP status of the last response, `None` if there was no response
+    pub status: Option<u16>,
+    /// Validators of the fetched feed, `None` if it was not modified or failed
+    pub cache: Option<FeedCache>,
+    pub entries: Vec<MediaEntry>,
+    pub error: Option<String>,
+}
+
+/// Fetches the feeds of sources while limiting the requests to each host. Backoffs requested by
+/// hosts are remembered between syncs.
+#[derive(Default)]
+pub struct Crawler {
+    client: Client,
+    /// Time until which each host asked not to receive any requests
+    backoffs: std::sync::Mutex<HashMap<String, Instant>>,
+}
+
+/// Limits the requests to a single host during a crawl
+struct HostLimiter {
+    permits: Semaphore,
+    interval: std::time::Duration,
+    next_request: Mutex<Instant>,
+}
+
+impl HostLimiter {
+    /// Waits until the next request to the host may be sent, fails if that is more than
+    /// `max_wait` from now
+    async fn wait(&self, max_wait: std::time::Duration) -> Result<(), Error> {
+        let mut next_request = self.next_request.lock().await;
+        let now = Instant::now();
+        if *next_request > now + max_wait {
+            return Err(Error::Custom(format!(
+                "Host is rate limited for another {}s",
+                (*next_request - now).as_secs()
+            )));
+        }
+        let start = (*next_request).max(now);
+        *next_request = start + self.interval;
+        drop(next_request);
+        time::sleep_until(start).await;
+        Ok(())
+    }
+}
+
+/// Response to a feed request
+enum Fetched {
+    Feed(FeedCache, Vec<MediaEntry>),
+    NotModified,
+    /// The host responded with `429 Too Many Requests`, with the time to wait if it was given
+    RateLimited(Option<std::time::Duration>),
+}
+
+/// Number of times a feed is requested again after the host asked to back off
+const RATE_LIMIT_RETRIES: usize = 3;
+/// Time to back off when a host does not say how long to wait
+const DEFAULT_BACKOFF: std::time::Duration = std::time::Duration::from_secs(60);
+
+impl Crawler {
+    pub async fn crawl(
+        &self,
+        mut sources: Vec<Source>,
+        caches: HashMap<String, FeedCache>,
+        config: &CrawlConfig,
+    ) -> Vec<FeedResult> {
+        sources.retain(|s| !s.paused);
+        debug!("Crawling feeds from {} sources..", sources.len());
+        let now = Instant::now();
+        let mut hosts = HashMap::new();
+        for source in &sources {
+            let host = host_name(&source.url);
+            let backoff = self.backoffs.lock().unwrap().get(&host).copied();
+            hosts.entry(host).or_insert_with_key(|host| {
+                let (concurrency, interval) = config.host_limits(host);
+                HostLimiter {
+                    permits: Semaphore::new(concurrency.max(1)),
+                    interval: interval.to_std().unwrap_or_default(),
+                    next_request: Mutex::new(backoff.unwrap_or(now).max(now)),
+                }
+            });
+        }
+        // Requests only take a global slot once their host allows them, so sources waiting
+        // for a slow or rate limiting host don't hold up the other hosts
+        let slots = Semaphore::new(config.concurrency.max(1));
+        let results = futures::future::join_all(sources.iter().map(|source| {
+            let host = host_name(&source.url);
+            let cache = caches.get(&source.url);
+            let hosts = &hosts;
+            let slots = &slots;
             async move {
-                match get_source_entries(client, source).await {
-                    Ok(m) => items.lock().await.extend(m),
-                    Err(e) => error!("Failed to get downloads for '{}': {e}", source.url),
+                let time = Utc::now();
+                let start = Instant::now();
+                let mut status = None;
+                let fetched = self
+                    .fetch(source, cache, &hosts[&host], slots, config, &mut status)
+                    .await;
+                let mut result = FeedResult {
+                    url: source.url.clone(),
+                    time,
+                    duration: start.elapsed(),
+                    status,
+                    cache: None,
+                    entries: Vec::new(),
+                    error: None,
+                };
+                match fetched {
+                    Ok((cache, entries)) => {
+                        result.cache = cache;
+                        result.entries = entries;
+                    }
+                    Err(e) => {
+                        error!("Failed to get downloads for '{}': {e}", source.url);
+                        result.error = Some(e.to_string());
+                    }
                 }
+                result
             }
-        })
+        }))
         .await;
+        debug!("Crawled {} sources", results.len());
+        results
+    }
 
-    let items = items.lock().await.to_vec();
-    debug!("Got {} entries from {} sources", items.len(), sources.len());
-    items
+    async fn fetch(
+        &self,
+        source: &Source,
+        cache: Option<&FeedCache>,
+        limiter: &HostLimiter,
+        slots: &Semaphore,
+        config: &CrawlConfig,
+        status: &mut Option<u16>,
+    ) -> Result<(Option<FeedCache>, Vec<MediaEntry>), Error> {
+        let _permit = limiter
+            .permits
+            .acquire()
+            .await
+            .map_err(|e| Error::Custom(e.to_string()))?;
+        let host = host_name(&source.url);
+        let max_backoff = config.max_backoff.to_std().unwrap_or_default();
+        for _ in 0..=RATE_LIMIT_RETRIES {
+            limiter.wait(max_backoff).await?;
+            let slot = slots
+                .acquire()
+                .await
+                .map_err(|e| Error::Custom(e.to_string()))?;
+            let fetched = get_source_entries(&self.client, source, cache, status).await?;
+            drop(slot);
+            match fetched {
+                Fetched::Feed(cache, entries) => return Ok((Some(cache), entries)),
+                Fetched::NotModified => return Ok((None, Vec::new())),
+                Fetched::RateLimited(retry_after) => {
+                    let retry_after = retry_after.unwrap_or(DEFAULT_BACKOFF);
+                    warn!(
+                        "Host '{host}' is rate limiting, backing off for {}s",
+                        retry_after.as_secs()
+                    );
+                    let until = Instant::now() + retry_after;
+                    let mut next_request = limiter.next_request.lock().await;
+                    *next_request = (*next_request).max(until);
+                    self.backoffs
+                        .lock()
+                        .unwrap()
+                        .insert(host.clone(), *next_request);
+                }
+            }
+        }
+        Err(Error::Custom(format!(
+            "Still rate limited after {RATE_LIMIT_RETRIES} retries"
+        )))
+    }
+}
+
+fn host_name(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Parses a `Retry-After` header, which is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<std::time::Duration> {
+    if let Ok(secs) = value.trim().parse() {
+        return Some(std::time::Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    // A date in the past allows retrying right away
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
 }
 
-async fn get_source_entries(client: Client, source: &Source) -> Result<Vec<MediaEntry>, Error> {
-    let response = client.get(&source.url).send().await?.error_for_status()?;
+async fn get_source_entries(
+    client: &Client,
+    source: &Source,
+    cache: Option<&FeedCache>,
+    status: &mut Option<u16>,
+) -> Result<Fetched, Error> {
+    let mut request = client.get(&source.url);
+    if let Some(etag) = cache.and_then(|c| c.etag.as_ref()) {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    if let Some(last_modified) = cache.and_then(|c| c.last_modified.as_ref()) {
+        request = request.header(IF_MODIFIED_SINCE, last_modified);
+    }
+    let response = request.send().await?;
+    *status = Some(response.status().as_u16());
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        return Ok(Fetched::RateLimited(retry_after));
+    }
+    let response = response.error_for_status()?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        debug!("Feed: {} was not modified", source.url);
+        return Ok(Fetched::NotModified);
+    }
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+    };
+    let cache = FeedCache {
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    };
     let xml = response.text().await?;
     let feed = parser::parse(xml.as_bytes())?;
     let mut items = Vec::new();
     for entry in feed.entries {
-        let dl = MediaEntry::from_feed_entry(entry, source.r#type.clone())?;
+        let dl = MediaEntry::from_feed_entry(entry, source)?;
         items.push(dl);
     }
     debug!("Feed: got {} entries from {}", items.len(), source.url);
-    Ok(items)
+    Ok(Fetched::Feed(cache, items))
 }
 
 fn dl_format(dl_type: SourceType) -> Vec<&'static str> {
@@ -76,53 +310,330 @@ struct DownloadInfo {
     #[serde(alias = "webpage_url")]
     pub source: String,
     pub id: String,
-    #[serde(alias = "filename")]
+    /// Final path after post-processing, `filename` still has the extension of the download
+    #[serde(rename = "filepath")]
     pub path: String,
     pub title: String,
-    pub description: String,
+    pub description: Option<String>,
 }
 
 impl DownloadInfo {
-    fn into_media(self) -> Media {
+    fn into_media(self, feed: Option<String>) -> Media {
         Media {
             source: self.source,
             id: self.id,
             path: self.path,
             title: self.title,
-            description: self.description, 
+            description: self.description.unwrap_or_default(),
             date: Utc::now().timestamp(),
+            feed,
+            protected: false,
+            missing: false,
+        }
+    }
+}
+
+/// Result of a finished download, sent back to the daemon along with the output of yt-dlp, if it
+/// could be started
+pub type DownloadResult = (MediaEntry, Result<Media, String>, Option<DownloadLog>);
+
+/// Directory inside the media directory where yt-dlp stores intermediate files
+const TEMP_DIR: &str = ".drainpipe";
+
+/// Prefix of the progress lines yt-dlp prints, followed by the progress as JSON
+const PROGRESS_PREFIX: &str = "[drainpipe-progress]";
+
+/// Progress of a download as reported by yt-dlp. Videos are downloaded as separate video and audio
+/// streams, so the progress starts over for each stream.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DownloadProgress {
+    pub downloaded_bytes: u64,
+    /// Total size of the stream, estimated if the server does not tell
+    pub total_bytes: Option<u64>,
+    pub percent: Option<f64>,
+    /// Download speed in bytes per second
+    pub speed: Option<f64>,
+    /// Estimated time until the stream is downloaded in seconds
+    pub eta: Option<u64>,
+}
+
+/// Progress fields of yt-dlp, depending on the downloader they are integers or floats
+#[derive(Deserialize)]
+struct YtDlpProgress {
+    downloaded_bytes: Option<f64>,
+    total_bytes: Option<f64>,
+    total_bytes_estimate: Option<f64>,
+    speed: Option<f64>,
+    eta: Option<f64>,
+}
+
+impl From<YtDlpProgress> for DownloadProgress {
+    fn from(p: YtDlpProgress) -> Self {
+        let downloaded_bytes = p.downloaded_bytes.unwrap_or_default() as u64;
+        let total_bytes = p.total_bytes.or(p.total_bytes_estimate).map(|t| t as u64);
+        Self {
+            downloaded_bytes,
+            total_bytes,
+            percent: total_bytes
+                .filter(|t| *t > 0)
+                .map(|t| (downloaded_bytes as f64 / t as f64 * 100.0).min(100.0)),
+            speed: p.speed,
+            eta: p.eta.map(|e| e as u64),
+        }
+    }
+}
+
+/// Minimum time between two progress events of a download
+const PROGRESS_EVENT_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Shares the progress of a download with its task and the event stream
+struct ProgressReporter {
+    entry: MediaEntry,
+    progress: Arc<std::sync::Mutex<Option<DownloadProgress>>>,
+    events: Events,
+    last_event: std::sync::Mutex<Option<Instant>>,
+}
+
+impl ProgressReporter {
+    fn report(&self, progress: DownloadProgress) {
+        *self.progress.lock().unwrap() = Some(progress.clone());
+        // yt-dlp reports the progress many times per second
+        let mut last_event = self.last_event.lock().unwrap();
+        if last_event.is_some_and(|t| t.elapsed() < PROGRESS_EVENT_INTERVAL) {
+            return;
         }
+        *last_event = Some(Instant::now());
+        self.events.send(Event::DownloadProgress {
+            id: self.entry.id,
+            link: self.entry.link.clone(),
+            progress,
+        });
     }
 }
 
-pub fn download_video(dir: String, entry: MediaEntry) -> JoinHandle<Result<Media, String>> {
-    thread::spawn(move || -> Result<Media, String> {
-        let output = Command::new("yt-dlp")
-            .args([
-                dl_format(entry.r#type),
-                vec![
-                    "--embed-thumbnail",
-                    "--embed-metadata",
-                    "--embed-info-json",
-                    "--print",
-                    "%()j",
-                    "--no-simulate",
-                    "--no-progress",
-                    "-o",
-                    &(dir + "/%(artist,channel,uploader|Unkown)s/%(release_date>%Y%m%d,upload_date>%Y%m%d)s-%(fulltitle)s.%(ext)s"),
-                    &entry.link,
-                ]
-            ].concat())
-            .output()
-            .map_err(|e| format!("Failed execute yt-dlp: {e}"))?;
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
-        if output.status.success() {
-            let info = serde_json::from_str::<DownloadInfo>(&stdout)
-                .map_err(|e| format!("Failed to parse JSON: {e}"))?;
-            Ok(info.into_media())
-        } else {
-            Err(format!("YT-DLP failed:\n{stderr}\n{stdout}",))
+/// A running yt-dlp download
+pub struct DownloadTask {
+    pub entry: MediaEntry,
+    /// Rate limit passed to yt-dlp, it can't be changed while the download is running
+    pub rate_limit: Option<u64>,
+    temp_dir: PathBuf,
+    handle: JoinHandle<()>,
+    /// Last progress reported by yt-dlp, `None` until the download starts
+    progress: Arc<std::sync::Mutex<Option<DownloadProgress>>>,
+}
+
+impl DownloadTask {
+    pub fn progress(&self) -> Option<DownloadProgress> {
+        self.progress.lock().unwrap().clone()
+    }
+
+    /// Kills the yt-dlp process with its children and removes its partial files
+    pub async fn kill(self) {
+        self.handle.abort();
+        // Wait until the task is dropped, which kills the process group
+        self.handle.await.ok();
+        match fs::remove_dir_all(&self.temp_dir) {
+            // yt-dlp did not create any files yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove partial files {:?}: {e}", self.temp_dir),
+            Ok(()) => {}
         }
+    }
+}
+
+/// Kills a process group when dropped. yt-dlp runs ffmpeg as child processes, which would keep
+/// writing partial files if only yt-dlp was killed.
+struct ProcessGroupGuard(Option<i32>);
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            // SAFETY: killpg only sends a signal and has no memory effects
+            unsafe { libc::killpg(pgid, libc::SIGKILL) };
+        }
+    }
+}
+
+pub fn download_video(
+    media_dir: PathBuf,
+    entry: MediaEntry,
+    rate_limit: Option<u64>,
+    done: UnboundedSender<DownloadResult>,
+    events: Events,
+) -> DownloadTask {
+    // Every entry has its own directory for intermediate files, so they can be cleaned up when the
+    // download is killed
+    let dir_name: String = entry
+        .link
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let temp_dir = media_dir.join(TEMP_DIR).join(dir_name);
+    let progress = Arc::default();
+    let handle = {
+        let entry = entry.clone();
+        let temp_dir = temp_dir.clone();
+        let reporter = ProgressReporter {
+            entry: entry.clone(),
+            progress: Arc::clone(&progress),
+            events,
+            last_event: Default::default(),
+        };
+        tokio::spawn(async move {
+            let (result, log) =
+                match run_yt_dlp(&media_dir, &temp_dir, &entry, rate_limit, &reporter).await {
+                    Ok(log) => (parse_output(&log, &entry), Some(log)),
+                    Err(e) => (Err(e), None),
+                };
+            fs::remove_dir_all(&temp_dir).ok();
+            done.send((entry, result, log)).ok();
+        })
+    };
+    DownloadTask {
+        entry,
+        rate_limit,
+        temp_dir,
+        handle,
+        progress,
+    }
+}
+
+async fn run_yt_dlp(
+    media_dir: &Path,
+    temp_dir: &Path,
+    entry: &MediaEntry,
+    rate_limit: Option<u64>,
+    progress: &ProgressReporter,
+) -> Result<DownloadLog, String> {
+    let mut cmd = std::process::Command::new("yt-dlp");
+    cmd.args(dl_format(entry.r#type.clone()))
+        .args([
+            "--embed-thumbnail",
+            "--embed-metadata",
+            "--embed-info-json",
+            // Printed once the file is moved to its final place, earlier the path may not match
+            "--print",
+            "after_move:%(.{webpage_url,id,filepath,title,description})j",
+            "--no-simulate",
+            // --print implies --quiet, which hides the progress
+            "--progress",
+            "--newline",
+            "--progress-template",
+            &format!("download:{PROGRESS_PREFIX}%(progress)j"),
+        ])
+        .arg("-P")
+        .arg(format!("home:{}", media_dir.to_string_lossy()))
+        .arg("-P")
+        .arg(format!("temp:{}", temp_dir.to_string_lossy()))
+        .args(
+            rate_limit
+                .map(|r| vec!["--limit-rate".to_string(), r.to_string()])
+                .unwrap_or_default(),
+        )
+        .args([
+            "-o",
+            "%(artist,channel,uploader|Unkown)s/%(release_date>%Y%m%d,upload_date>%Y%m%d)s-%(fulltitle)s.%(ext)s",
+            &entry.link,
+        ])
+        // Run in a separate process group, so a Ctrl-C in the terminal only reaches the daemon,
+        // which decides when to stop the download
+        .process_group(0);
+    let mut child = Command::from(cmd)
+        .kill_on_drop(true)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed execute yt-dlp: {e}"))?;
+    // yt-dlp leads its own process group, which is killed if the download is aborted
+    let mut group = ProcessGroupGuard(child.id().map(|id| id as i32));
+    // Both outputs are read while yt-dlp runs, it prints the progress to stderr in quiet mode
+    let (stdout, stderr, status) = tokio::join!(
+        read_output(child.stdout.take(), progress),
+        read_output(child.stderr.take(), progress),
+        child.wait()
+    );
+    // yt-dlp waits for its children, so the group is gone once it exited and its ID may be reused
+    group.0 = None;
+    let status = status.map_err(|e| format!("Failed execute yt-dlp: {e}"))?;
+    Ok(DownloadLog {
+        time: Utc::now(),
+        success: status.success(),
+        stdout,
+        stderr,
     })
 }
+
+/// Reads the downloaded media from the output of yt-dlp
+fn parse_output(log: &DownloadLog, entry: &MediaEntry) -> Result<Media, String> {
+    if log.success {
+        let info = serde_json::from_str::<DownloadInfo>(&log.stdout)
+            .map_err(|e| format!("Failed to parse JSON: {e}"))?;
+        Ok(info.into_media(entry.source.clone()))
+    } else {
+        Err(format!("YT-DLP failed:\n{}\n{}", log.stderr, log.stdout))
+    }
+}
+
+/// Reads an output of yt-dlp line by line, updates the progress from progress lines and returns
+/// the other lines
+async fn read_output(
+    output: Option<impl AsyncRead + Unpin>,
+    progress: &ProgressReporter,
+) -> String {
+    let Some(output) = output else {
+        return String::new();
+    };
+    let mut reader = BufReader::new(output);
+    let mut text = String::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Failed to read yt-dlp output: {e}");
+                break;
+            }
+        }
+        let line = String::from_utf8_lossy(&line);
+        match line.trim_end().strip_prefix(PROGRESS_PREFIX) {
+            Some(json) => match serde_json::from_str::<YtDlpProgress>(json) {
+                Ok(p) => progress.report(p.into()),
+                Err(e) => debug!("Invalid yt-dlp progress '{json}': {e}"),
+            },
+            None => text.push_str(&line),
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn retry_after_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_http_date() {
+        let date = (Utc::now() + chrono::Duration::seconds(90)).to_rfc2822();
+        let wait = parse_retry_after(&date).unwrap();
+        assert!(wait > Duration::from_secs(85) && wait <= Duration::from_secs(90));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn retry_after_invalid() {
+        assert_eq!(parse_retry_after(""), None);
+        assert_eq!(parse_retry_after("-5"), None);
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+}
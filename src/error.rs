@@ -18,6 +18,8 @@ pub enum Error {
     Feed(#[from] feed_rs::parser::ParseFeedError),
     #[error("Sqlite error: {0}")]
     Sqlite(#[from] sqlx::Error),
+    #[error("Not found: {0}")]
+    NotFound(String),
     #[error("{0}")]
     Custom(String),
 }
@@ -31,11 +33,12 @@ use tracing::error;
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
+        let status = match self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
         let err = self.to_string();
         error!("{err}");
-        Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(boxed(err))
-            .unwrap()
+        Response::builder().status(status).body(boxed(err)).unwrap()
     }
 }
@@ -0,0 +1,70 @@
+use crate::{
+    dl::DownloadProgress,
+    media::{Media, MediaEntry},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of events kept for subscribers that fall behind
+const CAPACITY: usize = 256;
+
+/// A change of the daemon state, pushed to the subscribers of the event stream
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// Entries were added to the download queue
+    Queued {
+        entries: Vec<MediaEntry>,
+    },
+    DownloadStarted {
+        entry: MediaEntry,
+    },
+    DownloadProgress {
+        id: Option<i64>,
+        link: String,
+        progress: DownloadProgress,
+    },
+    DownloadCompleted {
+        entry: MediaEntry,
+        media: Media,
+    },
+    DownloadFailed {
+        entry: MediaEntry,
+        error: String,
+        /// `None` if the download failed permanently
+        retry_at: Option<DateTime<Utc>>,
+    },
+    SyncStarted {
+        sources: Vec<String>,
+    },
+    SyncFinished {
+        entries: usize,
+        queued: usize,
+        /// Number of sources that failed to sync
+        failed: usize,
+    },
+    ConfigReloaded,
+    SourcesReloaded,
+}
+
+/// Broadcasts events to all subscribers
+#[derive(Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Sends an event to the current subscribers, it is dropped if there are none
+    pub fn send(&self, event: Event) {
+        self.0.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
@@ -114,3 +114,134 @@ pub mod dhms_duration_option {
         }
     }
 }
+
+pub mod cron_schedule_option {
+    use cron::Schedule;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::str::FromStr;
+
+    pub fn serialize<S>(schedule: &Option<Schedule>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match schedule {
+            Some(schedule) => serializer.serialize_str(&schedule.to_string()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Schedule>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(deserializer)? {
+            Some(str) => Ok(Some(Schedule::from_str(&str).map_err(Error::custom)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Byte sizes like `500K`, `2.5M` or `20GB`, using binary units
+pub mod byte_size_option {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    const UNITS: [&str; 5] = ["", "K", "M", "G", "T"];
+
+    pub fn serialize<S>(size: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match size {
+            Some(mut size) => {
+                let mut unit = 0;
+                while size >= 1024 && size % 1024 == 0 && unit < UNITS.len() - 1 {
+                    size /= 1024;
+                    unit += 1;
+                }
+                serializer.serialize_str(&format!("{size}{}", UNITS[unit]))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Size {
+            Bytes(u64),
+            String(String),
+        }
+
+        let str = match Option::<Size>::deserialize(deserializer)? {
+            Some(Size::Bytes(bytes)) => return Ok(Some(bytes)),
+            Some(Size::String(str)) => str,
+            None => return Ok(None),
+        };
+        let upper = str.trim().to_uppercase();
+        let num = upper.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let unit = upper[num.len()..]
+            .trim_end_matches('B')
+            .trim_end_matches('I');
+        let exp = UNITS
+            .iter()
+            .position(|u| *u == unit)
+            .ok_or_else(|| Error::custom(format!("Invalid unit in size '{str}'")))?;
+        let num: f64 = num.trim().parse().map_err(Error::custom)?;
+        if !num.is_finite() || num < 0.0 {
+            return Err(Error::custom(format!("Invalid size '{str}'")));
+        }
+        Ok(Some((num * 1024f64.powi(exp as i32)) as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Limit {
+        #[serde(default, with = "super::byte_size_option")]
+        size: Option<u64>,
+    }
+
+    fn parse(yaml: &str) -> Result<Option<u64>, serde_yaml::Error> {
+        serde_yaml::from_str::<Limit>(yaml).map(|l| l.size)
+    }
+
+    #[test]
+    fn parse_byte_size() {
+        assert_eq!(parse("size: 1000").unwrap(), Some(1000));
+        assert_eq!(parse("size: '512'").unwrap(), Some(512));
+        assert_eq!(parse("size: 2K").unwrap(), Some(2048));
+        assert_eq!(parse("size: 2m").unwrap(), Some(2 * 1024 * 1024));
+        assert_eq!(parse("size: 1.5 MiB").unwrap(), Some(3 * 512 * 1024));
+        assert_eq!(parse("size: 20GB").unwrap(), Some(20 * 1024 * 1024 * 1024));
+        assert_eq!(parse("size: 1T").unwrap(), Some(1024u64.pow(4)));
+        assert_eq!(parse("size: null").unwrap(), None);
+        assert_eq!(parse("{}").unwrap(), None);
+    }
+
+    #[test]
+    fn parse_invalid_byte_size() {
+        assert!(parse("size: 2X").is_err());
+        assert!(parse("size: M").is_err());
+        assert!(parse("size: -1M").is_err());
+        assert!(parse("size: 1.2.3K").is_err());
+    }
+
+    #[test]
+    fn serialize_byte_size() {
+        let to_yaml = |size| serde_yaml::to_string(&Limit { size }).unwrap();
+        assert_eq!(to_yaml(Some(2 * 1024 * 1024)), "size: 2M\n");
+        assert_eq!(to_yaml(Some(1536)), "size: '1536'\n");
+        assert_eq!(to_yaml(Some(0)), "size: '0'\n");
+        assert_eq!(to_yaml(None), "size: null\n");
+        // Sizes round trip
+        for size in [1, 1000, 1024, 1536, 3 * 1024 * 1024 * 1024] {
+            assert_eq!(parse(&to_yaml(Some(size))).unwrap(), Some(size));
+        }
+    }
+}
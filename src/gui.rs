@@ -1,47 +1,118 @@
 use crate::{
     config::ConfigData,
-    daemon::State,
+    daemon::{self, Command, Pause, PreviewEntry, State},
     db::Database,
+    dl::{Crawler, DownloadProgress},
     error::Error,
-    media::{Media, MediaEntry},
-    source::Source,
+    media::{DownloadLog, Media, MediaEntry},
+    source::{Source, SyncRun},
 };
 use axum::{
     body::{boxed, Full},
+    extract::{Path, Query},
     http::{header, StatusCode, Uri},
-    response::Response,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        Response,
+    },
     routing::{get, post},
     Extension, Json, Router,
 };
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream};
 use reqwest::Url;
 use rust_embed::RustEmbed;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::Mutex;
-use tracing::info;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc::UnboundedSender, Mutex},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
 
-pub fn start(port: u16, db: Arc<Database>, state: Arc<Mutex<State>>) {
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    info!("Starting web interface on: http://{addr}");
+/// The HTTP server of the web interface and API
+pub struct WebServer {
+    app: Router,
+    addr: SocketAddr,
+    /// Address from the config, differs from `addr` if binding to it failed
+    configured_addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
 
-    let app = Router::new()
-        .route("/sources", post(set_sources))
-        .route("/sources", get(get_sources))
-        .route("/state", get(get_state))
-        .route("/config", get(get_config))
-        .route("/config", post(set_config))
-        .route("/yt_feed", post(yt_feed))
-        .fallback(handler)
-        .layer(Extension(db))
-        .layer(Extension(state));
+impl WebServer {
+    pub fn start(
+        addr: SocketAddr,
+        db: Arc<Database>,
+        state: Arc<Mutex<State>>,
+        cmd_tx: UnboundedSender<Command>,
+        crawler: Arc<Crawler>,
+    ) -> Result<Self, Error> {
+        let app = Router::new()
+            .route("/sources", post(set_sources))
+            .route("/sources", get(get_sources))
+            .route("/sources/:id/history", get(get_source_history))
+            .route("/downloads/log", get(get_download_log))
+            .route("/state", get(get_state))
+            .route("/events", get(events))
+            .route("/sync/preview", post(preview_sync))
+            .route("/config", get(get_config))
+            .route("/config", post(set_config))
+            .route("/pause", post(set_paused))
+            .route("/cancel", post(cancel))
+            .route("/library/protect", post(protect))
+            .route("/library/reconcile", post(reconcile))
+            .route("/library/import", post(import))
+            .route("/yt_feed", post(yt_feed))
+            .fallback(handler)
+            .layer(Extension(db))
+            .layer(Extension(state))
+            .layer(Extension(cmd_tx))
+            .layer(Extension(crawler));
+        let handle = serve(app.clone(), addr)?;
+        Ok(Self {
+            app,
+            addr,
+            configured_addr: addr,
+            handle,
+        })
+    }
 
-    tokio::spawn(async move {
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
-            .await
-            .unwrap();
-    });
+    /// Moves the server to a new address, it stays on the old address if binding fails
+    pub async fn rebind(&mut self, addr: SocketAddr) {
+        if addr == self.configured_addr {
+            return;
+        }
+        self.configured_addr = addr;
+        // Aborting the server only stops accepting connections, requests that are being handled
+        // run in their own tasks and are completed
+        self.handle.abort();
+        (&mut self.handle).await.ok();
+        match serve(self.app.clone(), addr) {
+            Ok(handle) => {
+                self.handle = handle;
+                self.addr = addr;
+            }
+            Err(e) => {
+                error!("{e}, staying on http://{}", self.addr);
+                match serve(self.app.clone(), self.addr) {
+                    Ok(handle) => self.handle = handle,
+                    Err(e) => error!("{e}"),
+                }
+            }
+        }
+    }
+}
+
+fn serve(app: Router, addr: SocketAddr) -> Result<JoinHandle<()>, Error> {
+    let server = axum::Server::try_bind(&addr)
+        .map_err(|e| Error::Custom(format!("Failed to bind web interface to {addr}: {e}")))?;
+    info!("Starting web interface on: http://{addr}");
+    Ok(tokio::spawn(async move {
+        if let Err(e) = server.serve(app.into_make_service()).await {
+            error!("Web interface stopped: {e}");
+        }
+    }))
 }
 
 #[derive(RustEmbed)]
@@ -70,10 +141,30 @@ async fn handler(uri: Uri) -> Response {
     }
 }
 
+#[derive(Serialize)]
+struct PlannedSync {
+    url: String,
+    /// `None` if the source is paused
+    next_sync: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct TaskResponse {
+    #[serde(flatten)]
+    entry: MediaEntry,
+    progress: Option<DownloadProgress>,
+}
+
 #[derive(Serialize)]
 struct StateResponse {
-    tasks: Vec<MediaEntry>,
+    paused: Pause,
+    free_space: Option<u64>,
+    low_disk_space: bool,
+    disk_space_error: Option<String>,
+    syncs: Vec<PlannedSync>,
+    tasks: Vec<TaskResponse>,
     queue: Vec<MediaEntry>,
+    failed: Vec<MediaEntry>,
     library: Vec<Media>,
 }
 
@@ -82,26 +173,152 @@ async fn get_state(
     Extension(db): Extension<Arc<Database>>,
 ) -> Result<Json<StateResponse>, Error> {
     let state = state.lock().await;
-    let tasks = state.dl_tasks.iter().map(|(l, _)| l.clone()).collect();
-    let queue = Vec::from_iter(state.dl_queue.clone().into_iter());
-    let library = db.get_all().await?;
-
-    Ok(Json(StateResponse {
+    let tasks = state
+        .dl_tasks
+        .iter()
+        .map(|t| TaskResponse {
+            entry: t.entry.clone(),
+            progress: t.progress(),
+        })
+        .collect();
+    let queue = Vec::from_iter(state.dl_queue.clone());
+    let now = Utc::now();
+    let syncs = state
+        .sources
+        .as_slice()
+        .iter()
+        .map(|s| PlannedSync {
+            url: s.url.clone(),
+            next_sync: state
+                .scheduler
+                .next_sync(s, &state.config.data)
+                .map(|t| t.max(now)),
+        })
+        .collect();
+    let mut response = StateResponse {
+        paused: state.paused,
+        free_space: state.free_space,
+        low_disk_space: state.low_disk_space,
+        disk_space_error: state.disk_space_error.clone(),
+        syncs,
         tasks,
         queue,
-        library,
-    }))
+        failed: Vec::new(),
+        library: Vec::new(),
+    };
+    // The daemon can't go on while the state is locked, so it is released before the slower
+    // database queries
+    drop(state);
+    response.failed = db.get_failed().await?;
+    response.library = db.get_all().await?;
+    Ok(Json(response))
+}
+
+#[derive(Serialize)]
+struct SourceResponse {
+    /// ID used by the source endpoints, `None` if the source was never synced
+    id: Option<i64>,
+    #[serde(flatten)]
+    source: Source,
+    /// Number of failed syncs since the last successful one
+    failures: u32,
+    last_success: Option<DateTime<Utc>>,
+    unhealthy: bool,
 }
 
-async fn get_sources(Extension(state): Extension<Arc<Mutex<State>>>) -> Json<Vec<Source>> {
-    Json(state.lock().await.sources.get())
+/// Streams the changes of the daemon state as server-sent events
+async fn events(
+    Extension(state): Extension<Arc<Mutex<State>>>,
+) -> Sse<impl Stream<Item = Result<sse::Event, serde_json::Error>>> {
+    let rx = state.lock().await.events.subscribe();
+    let stream = stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((sse::Event::default().json_data(event), rx)),
+                Err(RecvError::Lagged(n)) => warn!("Event stream fell behind, skipped {n} events"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Crawls all sources and returns what a sync would do with each entry
+async fn preview_sync(
+    Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(crawler): Extension<Arc<Crawler>>,
+) -> Result<Json<Vec<PreviewEntry>>, Error> {
+    Ok(Json(daemon::preview_sync(&state, &db, &crawler).await?))
+}
+
+async fn get_sources(
+    Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(db): Extension<Arc<Database>>,
+) -> Result<Json<Vec<SourceResponse>>, Error> {
+    let health = db.get_source_health().await?;
+    let state = state.lock().await;
+    let unhealthy_after = state.config.data.unhealthy_after;
+    Ok(Json(
+        state
+            .sources
+            .get()
+            .into_iter()
+            .map(|source| {
+                let health = health.get(&source.url);
+                let failures = health.map(|h| h.failures).unwrap_or(0);
+                SourceResponse {
+                    id: health.map(|h| h.id),
+                    source,
+                    failures,
+                    last_success: health.and_then(|h| h.last_success),
+                    unhealthy: failures >= unhealthy_after.max(1),
+                }
+            })
+            .collect(),
+    ))
+}
+
+async fn get_source_history(
+    Extension(db): Extension<Arc<Database>>,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<SyncRun>>, Error> {
+    let runs = db
+        .get_sync_runs(id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("No source with ID {id}")))?;
+    Ok(Json(runs))
+}
+
+#[derive(Deserialize)]
+struct LogQuery {
+    link: String,
+}
+
+/// Returns the output of all stored download attempts of an entry, whether it is still queued or
+/// already in the library. Logs are looked up by link because the queue ID is gone once the
+/// download succeeded.
+async fn get_download_log(
+    Extension(db): Extension<Arc<Database>>,
+    Query(query): Query<LogQuery>,
+) -> Result<Json<Vec<DownloadLog>>, Error> {
+    let logs = db.get_download_logs(&query.link).await?;
+    if logs.is_empty() {
+        return Err(Error::NotFound(format!(
+            "No download log for '{}'",
+            query.link
+        )));
+    }
+    Ok(Json(logs))
 }
 
 async fn set_sources(
     Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(cmd_tx): Extension<UnboundedSender<Command>>,
     Json(sources): Json<Vec<Source>>,
 ) -> Result<(), Error> {
-    state.lock().await.sources.set(sources)
+    state.lock().await.sources.set(sources)?;
+    send_command(&cmd_tx, Command::Update)
 }
 
 async fn get_config(Extension(state): Extension<Arc<Mutex<State>>>) -> Json<ConfigData> {
@@ -110,9 +327,126 @@ async fn get_config(Extension(state): Extension<Arc<Mutex<State>>>) -> Json<Conf
 
 async fn set_config(
     Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(cmd_tx): Extension<UnboundedSender<Command>>,
     Json(config): Json<ConfigData>,
 ) -> Result<(), Error> {
-    state.lock().await.config.set(config)
+    state.lock().await.config.set(config)?;
+    send_command(&cmd_tx, Command::Update)
+}
+
+async fn set_paused(
+    Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(cmd_tx): Extension<UnboundedSender<Command>>,
+    Json(paused): Json<Pause>,
+) -> Result<(), Error> {
+    db.set_paused(paused).await?;
+    state.lock().await.paused = paused;
+    info!("Pause state set to {paused:?}");
+    send_command(&cmd_tx, Command::Update)
+}
+
+#[derive(Deserialize)]
+struct CancelRequest {
+    link: Option<String>,
+    id: Option<i64>,
+    /// Never queue the entry again
+    #[serde(default)]
+    block: bool,
+}
+
+async fn cancel(
+    Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(db): Extension<Arc<Database>>,
+    Extension(cmd_tx): Extension<UnboundedSender<Command>>,
+    Json(req): Json<CancelRequest>,
+) -> Result<(), Error> {
+    let (entry, task) = state
+        .lock()
+        .await
+        .cancel(req.link.as_deref(), req.id)
+        .ok_or_else(|| Error::NotFound("Entry is not queued or being downloaded".to_string()))?;
+    if let Some(task) = task {
+        task.kill().await;
+    }
+    if req.block {
+        info!("Blocked '{}'", entry.link);
+        db.queue_block(&entry.link).await?;
+    } else {
+        db.queue_remove(&entry.link).await?;
+    }
+    send_command(&cmd_tx, Command::Update)
+}
+
+#[derive(Deserialize)]
+struct ProtectRequest {
+    link: String,
+    protected: bool,
+}
+
+/// Protects a download from being deleted by retention rules
+async fn protect(
+    Extension(db): Extension<Arc<Database>>,
+    Json(req): Json<ProtectRequest>,
+) -> Result<(), Error> {
+    if db.set_protected(&req.link, req.protected).await? {
+        Ok(())
+    } else {
+        Err(Error::NotFound(format!(
+            "'{}' is not in the library",
+            req.link
+        )))
+    }
+}
+
+/// Checks the library for downloads whose files were removed
+async fn reconcile(Extension(cmd_tx): Extension<UnboundedSender<Command>>) -> Result<(), Error> {
+    send_command(&cmd_tx, Command::Reconcile)
+}
+
+#[derive(Deserialize)]
+struct ImportRequest {
+    /// Directory to import, relative to the media directory or an absolute path inside it. The
+    /// whole media directory if not set.
+    path: Option<PathBuf>,
+}
+
+/// Starts importing media that were downloaded with yt-dlp before
+async fn import(
+    Extension(state): Extension<Arc<Mutex<State>>>,
+    Extension(db): Extension<Arc<Database>>,
+    Json(req): Json<ImportRequest>,
+) -> Result<(), Error> {
+    let media_dir = state.lock().await.config.data.media_dir.clone();
+    let dir = match req.path {
+        Some(path) => media_dir.join(path),
+        None => media_dir.clone(),
+    };
+    let not_found = |_| Error::NotFound(format!("{dir:?} is not a directory"));
+    let (media_dir, dir) = (
+        media_dir.canonicalize().map_err(not_found)?,
+        dir.canonicalize().map_err(not_found)?,
+    );
+    if !dir.is_dir() {
+        return Err(Error::NotFound(format!("{dir:?} is not a directory")));
+    }
+    if !dir.starts_with(&media_dir) {
+        return Err(Error::Custom(format!(
+            "Can only import from the media directory {media_dir:?}"
+        )));
+    }
+    tokio::spawn(async move {
+        if let Err(e) = crate::import::import(&db, &dir).await {
+            error!("Failed to import media from {dir:?}: {e}");
+        }
+    });
+    Ok(())
+}
+
+fn send_command(cmd_tx: &UnboundedSender<Command>, cmd: Command) -> Result<(), Error> {
+    cmd_tx
+        .send(cmd)
+        .map_err(|_| Error::Custom("Daemon is not running".to_string()))
 }
 
 #[derive(Deserialize)]
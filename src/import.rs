@@ -0,0 +1,137 @@
+use crate::{db::Database, error::Error, media::Media};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+/// Extensions of the files that are considered media
+const MEDIA_EXTENSIONS: &[&str] = &[
+    "mkv", "mka", "webm", "mp4", "m4a", "opus", "ogg", "mp3", "flac", "wav",
+];
+
+/// Metadata written by yt-dlp with `--write-info-json` or `--embed-info-json`
+#[derive(Deserialize)]
+struct InfoJson {
+    webpage_url: String,
+    id: String,
+    title: String,
+    description: Option<String>,
+}
+
+/// Adds the media files in a directory that were downloaded by yt-dlp to the library, so they are
+/// not downloaded again. Imported media don't belong to a source, so retention rules never apply
+/// to them.
+pub async fn import(db: &Database, dir: &Path) -> Result<(), Error> {
+    info!("Importing media from {dir:?}..");
+    // Paths in the library are absolute
+    let dir = &dir.canonicalize()?;
+    let files = {
+        let dir = dir.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut files = Vec::new();
+            find_media(&dir, &mut files).map(|_| files)
+        })
+        .await
+        .map_err(|e| Error::Custom(e.to_string()))??
+    };
+    let (mut imported, mut skipped) = (0, 0);
+    for path in files {
+        let Some(info) = read_info(&path).await else {
+            debug!("No info JSON found for {path:?}");
+            skipped += 1;
+            continue;
+        };
+        if db.get(&info.webpage_url).await?.is_some() {
+            skipped += 1;
+            continue;
+        }
+        let date = fs::metadata(&path)?
+            .modified()
+            .map(|t| chrono::DateTime::<chrono::Utc>::from(t).timestamp())
+            .unwrap_or_default();
+        let media = Media {
+            source: info.webpage_url,
+            id: info.id,
+            path: path.to_string_lossy().to_string(),
+            title: info.title,
+            description: info.description.unwrap_or_default(),
+            date,
+            ..Default::default()
+        };
+        db.insert(&media).await?;
+        info!("Imported '{}' from {path:?}", media.title);
+        imported += 1;
+    }
+    info!("Imported {imported} media from {dir:?}, skipped {skipped} files");
+    Ok(())
+}
+
+/// Recursively collects the media files in a directory, skipping hidden directories such as the
+/// temporary download directory
+fn find_media(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if path.is_dir() {
+            if !hidden {
+                find_media(&path, files)?;
+            }
+        } else if path
+            .extension()
+            .is_some_and(|e| MEDIA_EXTENSIONS.contains(&e.to_string_lossy().as_ref()))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the info JSON from the sidecar file next to the media file, or from the attachment
+/// embedded in it
+async fn read_info(path: &Path) -> Option<InfoJson> {
+    let sidecar = path.with_extension("info.json");
+    let json = match fs::read_to_string(&sidecar) {
+        Ok(json) => json,
+        Err(_) => read_embedded_info(path).await?,
+    };
+    match serde_json::from_str(&json) {
+        Ok(info) => Some(info),
+        Err(e) => {
+            warn!("Invalid info JSON for {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Extracts the info JSON that yt-dlp attached to a Matroska file with ffmpeg
+async fn read_embedded_info(path: &Path) -> Option<String> {
+    if !matches!(path.extension()?.to_str()?, "mkv" | "mka" | "webm") {
+        return None;
+    }
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let out = std::env::temp_dir().join(format!(
+        "drainpipe-info-{}-{}.json",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    // ffmpeg complains that no output file is given, but dumps the attachment anyway
+    Command::new("ffmpeg")
+        .args(["-v", "quiet", "-y", "-dump_attachment:m:filename:info.json"])
+        .arg(&out)
+        .arg("-i")
+        .arg(path)
+        .stdin(Stdio::null())
+        .status()
+        .await
+        .ok()?;
+    let json = fs::read_to_string(&out).ok();
+    fs::remove_file(&out).ok();
+    json
+}
@@ -1,36 +1,45 @@
+mod cli;
 mod config;
 mod daemon;
 mod db;
 mod dl;
 mod error;
+mod events;
 mod file;
 mod gui;
+mod import;
 mod media;
+mod schedule;
 mod source;
 
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Dirs;
 use owo_colors::OwoColorize;
+use std::process::ExitCode;
 use tracing_subscriber::{filter::EnvFilter, fmt, prelude::*};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+
     let filter = EnvFilter::builder()
         .with_default_directive("drainpipe=INFO".parse().unwrap())
         .with_env_var("DRAINPIPE_LOG")
         .from_env()
         .unwrap();
+    // Log to stderr, so the output of commands can be piped
     tracing_subscriber::registry()
-        .with(fmt::layer().with_target(false))
+        .with(fmt::layer().with_target(false).with_writer(std::io::stderr))
         .with(filter)
         .init();
 
-    let d = match daemon::Daemon::start().await {
-        Ok(d) => d,
+    let dirs = Dirs::new(cli.config_dir, cli.data_dir);
+    match cli::run(cli.command.unwrap_or(Command::Daemon), &dirs).await {
+        Ok(code) => code,
         Err(e) => {
-            eprintln!("{}: {}", "Failed to start daemon".red().bold(), e);
-            return;
+            eprintln!("{}: {}", "Error".red().bold(), e);
+            ExitCode::FAILURE
         }
-    };
-    if let Err(e) = d.run().await {
-        eprintln!("{}: {}", "Daemon error".red().bold(), e);
     }
 }
@@ -1,14 +1,59 @@
-use crate::{error::Error, source::SourceType};
+use crate::{
+    error::Error,
+    source::{Source, SourceType},
+};
 use chrono::prelude::*;
 use feed_rs::model::Entry as FeedEntry;
 use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, FromRow, Row};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct MediaEntry {
+    /// ID in the download queue, set once the entry is queued
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub title: Option<String>,
     pub link: String,
     pub published: Option<DateTime<Utc>>,
     pub r#type: SourceType,
+    /// URL of the source the entry comes from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+}
+
+/// Failed download attempts of an entry
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub attempts: u32,
+    pub error: String,
+    /// Time of the next attempt, `None` if the download failed permanently
+    pub retry_at: Option<DateTime<Utc>>,
+}
+
+/// Output of a yt-dlp run, without the progress lines
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadLog {
+    /// Time the download finished
+    pub time: DateTime<Utc>,
+    pub success: bool,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl<'r> FromRow<'r, SqliteRow> for DownloadLog {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            time: Utc
+                .timestamp_opt(row.try_get("time")?, 0)
+                .single()
+                .unwrap_or_default(),
+            success: row.try_get("success")?,
+            stdout: row.try_get("stdout")?,
+            stderr: row.try_get("stderr")?,
+        })
+    }
 }
 
 impl PartialEq for MediaEntry {
@@ -18,17 +63,56 @@ impl PartialEq for MediaEntry {
 }
 
 impl MediaEntry {
-    pub fn from_feed_entry(e: FeedEntry, r#type: SourceType) -> Result<Self, Error> {
+    pub fn from_feed_entry(e: FeedEntry, source: &Source) -> Result<Self, Error> {
         Ok(Self {
+            id: None,
             title: e.title.map(|t| t.content),
             published: e.published,
             link: e
                 .links
-                .get(0)
+                .first()
                 .ok_or_else(|| Error::Custom("No link on entry!".to_string()))?
                 .href
                 .clone(),
-            r#type,
+            r#type: source.r#type.clone(),
+            source: Some(source.url.clone()),
+            failure: None,
+        })
+    }
+
+    /// Whether the entry can be downloaded now, entries that failed earlier have to wait until
+    /// their retry time
+    pub fn is_ready(&self, now: DateTime<Utc>) -> bool {
+        match &self.failure {
+            Some(f) => f.retry_at.map(|t| t <= now).unwrap_or(false),
+            None => true,
+        }
+    }
+}
+
+impl<'r> FromRow<'r, SqliteRow> for MediaEntry {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            title: row.try_get("title")?,
+            link: row.try_get("link")?,
+            published: row
+                .try_get::<Option<i64>, _>("published")?
+                .and_then(|t| Utc.timestamp_opt(t, 0).single()),
+            r#type: row.try_get("type")?,
+            source: row.try_get("source")?,
+            failure: match row.try_get::<u32, _>("attempts")? {
+                0 => None,
+                attempts => Some(Failure {
+                    attempts,
+                    error: row
+                        .try_get::<Option<String>, _>("last_error")?
+                        .unwrap_or_default(),
+                    retry_at: row
+                        .try_get::<Option<i64>, _>("next_attempt")?
+                        .and_then(|t| Utc.timestamp_opt(t, 0).single()),
+                }),
+            },
         })
     }
 }
@@ -43,4 +127,13 @@ pub struct Media {
     pub title: String,
     pub description: String,
     pub date: i64,
+    /// URL of the source the media was downloaded from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feed: Option<String>,
+    /// Never deleted by retention rules
+    #[serde(default)]
+    pub protected: bool,
+    /// The file was not found in the media directory
+    #[serde(default)]
+    pub missing: bool,
 }
@@ -0,0 +1,224 @@
+use crate::{config::ConfigData, source::Source};
+use chrono::{prelude::*, Duration};
+use rand::Rng;
+use std::collections::HashMap;
+
+struct SourceSchedule {
+    last_sync: DateTime<Utc>,
+    /// Random delay added to the interval, so sources with the same interval are not all crawled
+    /// at once
+    jitter: Duration,
+}
+
+/// Keeps track of when each source was synced and when it is due again
+#[derive(Default)]
+pub struct Scheduler {
+    sources: HashMap<String, SourceSchedule>,
+}
+
+impl Scheduler {
+    /// Creates a scheduler from the last sync times of the sources
+    pub fn new(last_syncs: Vec<(String, DateTime<Utc>)>, config: &ConfigData) -> Self {
+        let sources = last_syncs
+            .into_iter()
+            .map(|(url, last_sync)| {
+                let schedule = SourceSchedule {
+                    last_sync,
+                    jitter: random_jitter(config),
+                };
+                (url, schedule)
+            })
+            .collect();
+        Self { sources }
+    }
+
+    /// Returns when the source should be synced next, sources that were never synced are due
+    /// right away. Paused sources are never due, neither are sources whose next sync is too far
+    /// in the future to be represented.
+    pub fn next_sync(&self, source: &Source, config: &ConfigData) -> Option<DateTime<Utc>> {
+        if source.paused {
+            return None;
+        }
+        let Some(s) = self.sources.get(&source.url) else {
+            return Some(DateTime::<Utc>::MIN_UTC);
+        };
+        // The most specific setting wins: the schedule or interval of the source, then the global
+        // schedule and finally the global interval
+        let schedule = match (&source.sync_schedule, source.sync_interval) {
+            (Some(schedule), _) => Some(schedule),
+            (None, Some(_)) => None,
+            (None, None) => config.sync_schedule.as_ref(),
+        };
+        let next = match schedule {
+            Some(schedule) => schedule
+                .after(&s.last_sync.with_timezone(&Local))
+                .next()?
+                .with_timezone(&Utc),
+            None => {
+                let interval = source.sync_interval.unwrap_or(config.sync_interval);
+                s.last_sync.checked_add_signed(seconds(interval)?)?
+            }
+        };
+        next.checked_add_signed(s.jitter)
+    }
+
+    /// Returns the earliest time any of the sources is due
+    pub fn next(&self, sources: &[Source], config: &ConfigData) -> Option<DateTime<Utc>> {
+        sources
+            .iter()
+            .filter_map(|s| self.next_sync(s, config))
+            .min()
+    }
+
+    /// Returns the sources that are due at `now`
+    pub fn due(
+        &self,
+        sources: Vec<Source>,
+        config: &ConfigData,
+        now: DateTime<Utc>,
+    ) -> Vec<Source> {
+        sources
+            .into_iter()
+            .filter(|s| self.next_sync(s, config).is_some_and(|t| t <= now))
+            .collect()
+    }
+
+    pub fn synced(&mut self, url: &str, time: DateTime<Utc>, config: &ConfigData) {
+        let schedule = SourceSchedule {
+            last_sync: time,
+            jitter: random_jitter(config),
+        };
+        self.sources.insert(url.to_string(), schedule);
+    }
+}
+
+fn random_jitter(config: &ConfigData) -> Duration {
+    seconds(rand::thread_rng().gen_range(0..=config.sync_jitter)).unwrap_or_else(Duration::zero)
+}
+
+/// Converts seconds to a duration, `None` if it is too long
+fn seconds(secs: u64) -> Option<Duration> {
+    let ms = i64::try_from(secs).ok()?.checked_mul(1000)?;
+    Some(Duration::milliseconds(ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceType;
+    use cron::Schedule;
+    use std::str::FromStr;
+
+    const URL: &str = "https://example.com/feed.xml";
+
+    fn config() -> ConfigData {
+        ConfigData {
+            sync_interval: 900,
+            sync_jitter: 0,
+            ..Default::default()
+        }
+    }
+
+    fn last_sync() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 1, 10, 0, 30).unwrap()
+    }
+
+    fn scheduler(config: &ConfigData) -> Scheduler {
+        Scheduler::new(vec![(URL.to_string(), last_sync())], config)
+    }
+
+    #[test]
+    fn never_synced_is_due() {
+        let config = config();
+        let source = Source::new(URL.to_string(), SourceType::Video);
+        let next = Scheduler::default().next_sync(&source, &config);
+        assert_eq!(next, Some(DateTime::<Utc>::MIN_UTC));
+    }
+
+    #[test]
+    fn paused_is_never_due() {
+        let config = config();
+        let mut source = Source::new(URL.to_string(), SourceType::Video);
+        source.paused = true;
+        assert_eq!(scheduler(&config).next_sync(&source, &config), None);
+    }
+
+    #[test]
+    fn global_interval() {
+        let config = config();
+        let source = Source::new(URL.to_string(), SourceType::Video);
+        let next = scheduler(&config).next_sync(&source, &config);
+        assert_eq!(next, Some(last_sync() + Duration::seconds(900)));
+    }
+
+    #[test]
+    fn huge_interval_is_never_due() {
+        let config = ConfigData {
+            sync_jitter: u64::MAX,
+            ..config()
+        };
+        let mut source = Source::new(URL.to_string(), SourceType::Video);
+        source.sync_interval = Some(u64::MAX);
+        assert_eq!(scheduler(&config).next_sync(&source, &config), None);
+        source.sync_interval = Some(i64::MAX as u64 / 1000);
+        assert_eq!(scheduler(&config).next_sync(&source, &config), None);
+    }
+
+    #[test]
+    fn source_interval_overrides_global_schedule() {
+        let config = ConfigData {
+            sync_schedule: Some(Schedule::from_str("0 0 0 * * *").unwrap()),
+            ..config()
+        };
+        let mut source = Source::new(URL.to_string(), SourceType::Video);
+        source.sync_interval = Some(60);
+        let next = scheduler(&config).next_sync(&source, &config);
+        assert_eq!(next, Some(last_sync() + Duration::seconds(60)));
+    }
+
+    #[test]
+    fn cron_next_run() {
+        let config = config();
+        let mut source = Source::new(URL.to_string(), SourceType::Video);
+        // Every full minute, which is the same in every time zone
+        source.sync_schedule = Some(Schedule::from_str("0 * * * * *").unwrap());
+        source.sync_interval = Some(60 * 60);
+        let next = scheduler(&config).next_sync(&source, &config);
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 1, 1, 10, 1, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn global_schedule_overrides_global_interval() {
+        let config = ConfigData {
+            sync_schedule: Some(Schedule::from_str("0 * * * * *").unwrap()),
+            ..config()
+        };
+        let source = Source::new(URL.to_string(), SourceType::Video);
+        let next = scheduler(&config).next_sync(&source, &config);
+        assert_eq!(
+            next,
+            Some(Utc.with_ymd_and_hms(2024, 1, 1, 10, 1, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn due_sources() {
+        let config = config();
+        let scheduler = scheduler(&config);
+        let synced = Source::new(URL.to_string(), SourceType::Video);
+        let new = Source::new("https://example.com/new.xml".to_string(), SourceType::Audio);
+        let sources = vec![synced, new];
+        let due = scheduler.due(sources.clone(), &config, last_sync());
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].url, "https://example.com/new.xml");
+        let later = last_sync() + Duration::seconds(900);
+        assert_eq!(scheduler.due(sources.clone(), &config, later).len(), 2);
+        assert_eq!(
+            scheduler.next(&sources, &config),
+            Some(DateTime::<Utc>::MIN_UTC)
+        );
+    }
+}
@@ -1,25 +1,118 @@
-use std::path::{Path, PathBuf};
+use crate::{config::Retention, error::Error};
+use chrono::prelude::*;
+use cron::Schedule;
 use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, FromRow, Row};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 use tracing::log::info;
-use crate::error::Error;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Source {
     pub url: String,
     pub r#type: SourceType,
+    /// Sync interval in seconds, overrides the global sync interval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync_interval: Option<u64>,
+    /// Cron expression, overrides the sync interval
+    #[serde(
+        default,
+        with = "crate::file::cron_schedule_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub sync_schedule: Option<Schedule>,
+    /// Maximum download rate in bytes per second, overrides the global rate limit
+    #[serde(
+        default,
+        with = "crate::file::byte_size_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rate_limit: Option<u64>,
+    /// Rules for deleting old downloads, overrides the global retention rules
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<Retention>,
+    /// Skip this source when syncing
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub paused: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+impl Source {
+    pub fn new(url: String, r#type: SourceType) -> Self {
+        Self {
+            url,
+            r#type,
+            sync_interval: None,
+            sync_schedule: None,
+            rate_limit: None,
+            retention: None,
+            paused: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
 pub enum SourceType {
     Video,
     Audio,
 }
 
+impl fmt::Display for SourceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceType::Video => write!(f, "video"),
+            SourceType::Audio => write!(f, "audio"),
+        }
+    }
+}
+
+/// Results of the recent syncs of a source
+#[derive(Debug, Clone)]
+pub struct SourceHealth {
+    /// ID of the source in the database
+    pub id: i64,
+    /// Number of failed syncs since the last successful one
+    pub failures: u32,
+    pub last_success: Option<DateTime<Utc>>,
+}
+
+/// A fetch of the feed of a source
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncRun {
+    pub time: DateTime<Utc>,
+    /// HTTP status of the response, `None` if there was no response
+    pub status: Option<u16>,
+    /// Number of entries in the feed
+    pub entries: u32,
+    /// Number of new entries added to the download queue
+    pub queued: u32,
+    /// Duration in milliseconds
+    pub duration: u64,
+    pub error: Option<String>,
+}
+
+impl<'r> FromRow<'r, SqliteRow> for SyncRun {
+    fn from_row(row: &'r SqliteRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            time: Utc
+                .timestamp_opt(row.try_get("time")?, 0)
+                .single()
+                .unwrap_or_default(),
+            status: row.try_get("status")?,
+            entries: row.try_get("entries")?,
+            queued: row.try_get("queued")?,
+            duration: row.try_get::<i64, _>("duration")? as u64,
+            error: row.try_get("error")?,
+        })
+    }
+}
+
 pub struct Sources {
     path: PathBuf,
     sources: Vec<Source>,
-    changed: bool,
 }
 
 impl Sources {
@@ -29,13 +122,11 @@ impl Sources {
         Ok(Self {
             path: path.to_path_buf(),
             sources,
-            changed: false,
         })
     }
 
     pub fn reload(&mut self) -> Result<(), Error> {
         self.sources = crate::file::load::<Vec<Source>>(&self.path)?;
-        self.changed = true;
         Ok(())
     }
 
@@ -43,18 +134,13 @@ impl Sources {
         self.sources.clone()
     }
 
+    pub fn as_slice(&self) -> &[Source] {
+        &self.sources
+    }
+
     pub fn set(&mut self, sources: Vec<Source>) -> Result<(), Error> {
         self.sources = sources;
         crate::file::save(&self.sources, &self.path)?;
-        self.changed = true;
         Ok(())
     }
-
-    pub fn changed(&mut self) -> bool {
-        if self.changed {
-            self.changed = false;
-            return true;
-        }
-        false
-    }
 }